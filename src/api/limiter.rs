@@ -0,0 +1,46 @@
+use crate::settings::PasswordConfig;
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+// (client, uuid)
+type AttemptKey = (Option<IpAddr>, String);
+
+// Counts failed password attempts per client and file
+pub struct AttemptLimiter {
+    // key -> (failures, window start)
+    attempts: Mutex<HashMap<AttemptKey, (usize, Instant)>>,
+    max_attempts: usize,
+    window: Duration,
+}
+
+impl AttemptLimiter {
+    pub fn new(conf: &PasswordConfig) -> Self {
+        Self {
+            attempts: Mutex::new(HashMap::new()),
+            max_attempts: conf.max_attempts,
+            window: Duration::from_secs(conf.attempt_window as u64),
+        }
+    }
+
+    pub fn is_limited(&self, client: Option<IpAddr>, uuid: &str) -> bool {
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        match attempts.get(&(client, uuid.to_string())) {
+            Some((failures, start)) => start.elapsed() < self.window && *failures >= self.max_attempts,
+            None => false,
+        }
+    }
+
+    pub fn record_failure(&self, client: Option<IpAddr>, uuid: &str) {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        // Stale windows are dropped here so the map doesn't grow forever
+        attempts.retain(|_, (_, start)| start.elapsed() < self.window);
+
+        let (failures, _) = attempts.entry((client, uuid.to_string())).or_insert((0, Instant::now()));
+        *failures += 1;
+    }
+
+    pub fn clear(&self, client: Option<IpAddr>, uuid: &str) {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.remove(&(client, uuid.to_string()));
+    }
+}
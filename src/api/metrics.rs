@@ -0,0 +1,145 @@
+use crate::cache::core::FileCache;
+use actix_web::{HttpResponse, body::BodySize, body::MessageBody, http::StatusCode};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Duration;
+
+// Upper bounds in seconds, uploads and downloads range from tiny files to large streams
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const OUTCOMES: [&str; 5] = ["ok", "rejected", "denied", "not_found", "error"];
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative per bucket, the last one is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, took: Duration) {
+        let seconds = took.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter().copied().chain([f64::INFINITY])) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter().map(|bound| bound.to_string()).chain(["+Inf".to_string()])) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed));
+    }
+}
+
+// One counter per entry of `OUTCOMES`
+#[derive(Default)]
+struct Outcomes([AtomicU64; OUTCOMES.len()]);
+
+impl Outcomes {
+    fn record(&self, status: StatusCode) {
+        let outcome = match status.as_u16() {
+            200..=299 => 0,
+            401 | 403 | 429 => 2,
+            404 | 410 => 3,
+            400..=499 | 507 => 1,
+            _ => 4,
+        };
+        self.0[outcome].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Request level counters, the cache keeps its own
+#[derive(Default)]
+pub struct Metrics {
+    uploads: Outcomes,
+    downloads: Outcomes,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    upload_latency: Histogram,
+    download_latency: Histogram,
+    // Shared with `IpWhitelist`
+    pub whitelist_denied: Arc<AtomicU64>,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+impl Metrics {
+    pub fn record_upload(&self, response: &HttpResponse, took: Duration, size: usize) {
+        self.uploads.record(response.status());
+        self.upload_latency.observe(took);
+        if response.status().is_success() {
+            self.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    // What goes out is whatever the body says it holds, streams included
+    pub fn record_download(&self, response: &HttpResponse, took: Duration) {
+        self.downloads.record(response.status());
+        self.download_latency.observe(took);
+        if response.status().is_success()
+            && let BodySize::Sized(size) = response.body().size()
+        {
+            self.bytes_out.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Prometheus text exposition format
+    pub async fn render(&self, cache: &FileCache) -> String {
+        let mut out = String::new();
+        for (name, help, outcomes) in [("korvatunturi_uploads_total", "Uploads by result", &self.uploads), ("korvatunturi_downloads_total", "Downloads by result", &self.downloads)] {
+            header(&mut out, name, "counter", help);
+            for (outcome, count) in OUTCOMES.iter().zip(outcomes.0.iter()) {
+                let _ = writeln!(out, "{}{{result=\"{}\"}} {}", name, outcome, count.load(Ordering::Relaxed));
+            }
+        }
+        sample(&mut out, "korvatunturi_received_bytes_total", "counter", "Bytes of accepted uploads", self.bytes_in.load(Ordering::Relaxed));
+        sample(&mut out, "korvatunturi_sent_bytes_total", "counter", "Bytes of served downloads", self.bytes_out.load(Ordering::Relaxed));
+        self.upload_latency.render(&mut out, "korvatunturi_upload_duration_seconds", "Time to take an upload");
+        self.download_latency.render(&mut out, "korvatunturi_download_duration_seconds", "Time until a download starts");
+
+        let stats = cache.cache_metrics().await;
+        header(
+            &mut out,
+            "korvatunturi_cache_reads_total",
+            "counter",
+            "Reads served from memory (hit) or after going to the library (miss), by how the content was handed out",
+        );
+        for (result, content, count) in [
+            ("hit", "in_memory", stats.hits_in_memory),
+            ("hit", "on_disk", stats.hits_on_disk),
+            ("miss", "in_memory", stats.misses_in_memory),
+            ("miss", "on_disk", stats.misses_on_disk),
+        ] {
+            let _ = writeln!(out, "korvatunturi_cache_reads_total{{result=\"{}\",content=\"{}\"}} {}", result, content, count);
+        }
+        let scrub = cache.scrub_status();
+        sample(&mut out, "korvatunturi_scrub_files_total", "counter", "Blobs checked by the integrity scrubber", scrub.files_checked());
+        sample(&mut out, "korvatunturi_scrub_bytes_total", "counter", "Bytes hashed by the integrity scrubber", scrub.bytes_checked());
+        sample(&mut out, "korvatunturi_scrub_corrupt_total", "counter", "Corrupted blobs found by the integrity scrubber", scrub.corrupt_found());
+        sample(&mut out, "korvatunturi_expired_total", "counter", "Entries removed after expiring", stats.expired);
+        sample(&mut out, "korvatunturi_burned_total", "counter", "Entries removed after their last allowed download", stats.burned);
+        sample(&mut out, "korvatunturi_live_entries", "gauge", "Entries available for download", stats.live_entries);
+        sample(&mut out, "korvatunturi_library_bytes", "gauge", "Size of the library as last seen by the disk monitor", cache.disk_usage().library_bytes);
+        let memory = cache.memory_usage().await;
+        sample(&mut out, "korvatunturi_cache_memory_bytes", "gauge", "File contents held in memory", memory.used_bytes);
+        sample(&mut out, "korvatunturi_cache_memory_max_bytes", "gauge", "Memory the cache may hold", memory.max_bytes);
+        let queue = cache.queue_report();
+        sample(&mut out, "korvatunturi_signal_queue_depth", "gauge", "Signals waiting for the background routines", queue.depth);
+        sample(&mut out, "korvatunturi_signal_queue_capacity", "gauge", "Signals the queue holds before handlers wait", queue.capacity);
+        sample(&mut out, "korvatunturi_whitelist_denied_total", "counter", "Requests refused by the IP whitelist", self.whitelist_denied.load(Ordering::Relaxed));
+        out
+    }
+}
@@ -1,21 +1,23 @@
 use actix_web::body::EitherBody;
 use actix_web::http::StatusCode;
 use actix_web::{
-    Error,
+    Error, HttpMessage,
     dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
 };
-use actix_web::{HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, web};
 use askama::Template;
 use ipnet::IpNet;
 use log::{debug, warn};
 use std::future::{Ready, ready};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{collections::HashSet, future::Future, pin::Pin};
 
 use crate::frontend::Forbidden;
+use crate::users::AuthenticatedUser;
 
-fn extract_client_ip(req: &ServiceRequest, header: &str) -> Option<String> {
+fn extract_client_ip(req: &HttpRequest, header: &str) -> Option<String> {
     if let Some(forwarded) = req.headers().get(header) {
         if let Ok(val) = forwarded.to_str() {
             return Some(val.to_string());
@@ -24,10 +26,35 @@ fn extract_client_ip(req: &ServiceRequest, header: &str) -> Option<String> {
     None
 }
 
+fn remote_address(req: &HttpRequest, header: &Option<String>) -> Option<String> {
+    match header {
+        Some(h) => extract_client_ip(req, h),
+        None => Some(req.connection_info().peer_addr().unwrap_or("<no_ip_found>").to_string()),
+    }
+}
+
+// Shared with the handlers that need to know who they are talking to
+#[derive(Clone)]
+pub struct ClientIp {
+    forwarded_header: Arc<Option<String>>,
+}
+
+impl ClientIp {
+    pub fn new(header: Option<String>) -> Self {
+        Self { forwarded_header: Arc::new(header) }
+    }
+
+    pub fn resolve(&self, req: &HttpRequest) -> Option<std::net::IpAddr> {
+        remote_address(req, &self.forwarded_header).and_then(|ip| std::net::IpAddr::from_str(&ip).ok())
+    }
+}
+
 #[derive(Clone)]
 pub struct IpWhitelist {
     allowed: Arc<HashSet<IpNet>>,
     forwarded_header: Arc<Option<String>>,
+    // Refused requests, exported by the metrics endpoint
+    denied: Arc<AtomicU64>,
 }
 
 impl IpWhitelist {
@@ -38,8 +65,14 @@ impl IpWhitelist {
         Self {
             allowed: Arc::new(ips.into_iter().collect()),
             forwarded_header: Arc::new(header),
+            denied: Arc::new(AtomicU64::new(0)),
         }
     }
+
+    pub fn with_denied_counter(mut self, denied: Arc<AtomicU64>) -> Self {
+        self.denied = denied;
+        self
+    }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for IpWhitelist
@@ -59,6 +92,7 @@ where
             service,
             allowed: self.allowed.clone(),
             header: self.forwarded_header.clone(),
+            denied: self.denied.clone(),
         }))
     }
 }
@@ -67,6 +101,7 @@ pub struct AccessControlMiddleware<S> {
     service: S,
     allowed: Arc<HashSet<IpNet>>,
     header: Arc<Option<String>>,
+    denied: Arc<AtomicU64>,
 }
 
 impl<S, B> Service<ServiceRequest> for AccessControlMiddleware<S>
@@ -86,10 +121,16 @@ where
         let server_info = req.app_data::<web::Data<(String, String)>>().cloned();
 
         // Cursed :-)
-        let remote_conn = match &*self.header {
-            Some(h) => extract_client_ip(&req, h),
-            None => Some(req.connection_info().peer_addr().unwrap_or("<no_ip_found>").to_string()),
-        };
+        let remote_conn = remote_address(req.request(), &self.header);
+
+        // Users authenticated by `UserAuth` don't need to be on the whitelist
+        if req.extensions().get::<AuthenticatedUser>().is_some() {
+            let fut = self.service.call(req);
+            return Box::pin(async move {
+                let res = fut.await?;
+                Ok(res.map_into_left_body())
+            });
+        }
 
         if let Some(remote_ip) = &remote_conn {
             if let Ok(ip) = std::net::IpAddr::from_str(&remote_ip) {
@@ -104,6 +145,7 @@ where
             }
         }
 
+        self.denied.fetch_add(1, Ordering::Relaxed);
         if let Some(data) = server_info {
             let page = Forbidden { server_name: &data.0 };
             if let Ok(page) = page.render() {
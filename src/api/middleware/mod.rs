@@ -1,2 +1,4 @@
 mod auth;
-pub use auth::IpWhitelist;
+mod session;
+pub use auth::{ClientIp, IpWhitelist};
+pub use session::{SESSION_COOKIE, UserAuth, authenticated_user};
@@ -0,0 +1,168 @@
+use super::ClientIp;
+use crate::api::limiter::AttemptLimiter;
+use crate::users::{AuthenticatedUser, Role, UserStore};
+use actix_web::body::EitherBody;
+use actix_web::http::{StatusCode, header};
+use actix_web::{
+    Error, HttpMessage, HttpRequest, HttpResponse,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use log::{debug, warn};
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub const SESSION_COOKIE: &str = "korvatunturi_session";
+
+// Username and password from an `Authorization: Basic` header
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// Token from an `Authorization: Bearer` header
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(|token| token.trim().to_string())
+}
+
+/// Authenticates users through a session cookie, Basic auth or an API token.
+/// Requests from users with the required role are marked with an `AuthenticatedUser`,
+/// everyone else is left for the `IpWhitelist` further in to decide on.
+#[derive(Clone)]
+pub struct UserAuth {
+    store: Arc<UserStore>,
+    // Basic auth failures count against the same limit as the login form
+    limiter: Arc<AttemptLimiter>,
+    client_ip: Arc<ClientIp>,
+    required: Role,
+}
+
+impl UserAuth {
+    pub fn new(store: Arc<UserStore>, limiter: Arc<AttemptLimiter>, client_ip: Arc<ClientIp>, required: Role) -> Self {
+        Self { store, limiter, client_ip, required }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for UserAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = UserAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UserAuthMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+            limiter: self.limiter.clone(),
+            client_ip: self.client_ip.clone(),
+            required: self.required,
+        }))
+    }
+}
+
+pub struct UserAuthMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<UserStore>,
+    limiter: Arc<AttemptLimiter>,
+    client_ip: Arc<ClientIp>,
+    required: Role,
+}
+
+impl<S, B> Service<ServiceRequest> for UserAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let limiter = self.limiter.clone();
+        let client_ip = self.client_ip.clone();
+        let required = self.required;
+
+        Box::pin(async move {
+            let session = req.cookie(SESSION_COOKIE).map(|c| c.value().to_string());
+            let basic = basic_credentials(req.request());
+            let bearer = bearer_token(req.request());
+
+            let mut user = match session {
+                Some(token) => store.session(&token).await,
+                None => None,
+            };
+            if user.is_none()
+                && let Some((username, password)) = &basic
+            {
+                let client = client_ip.resolve(req.request());
+                let limit_key = format!("login:{}", username);
+                // Checked before hashing so guessing can't be used to keep argon2 busy either
+                if limiter.is_limited(client, &limit_key) {
+                    warn!("{:?}: too many failed Basic authentications for {}", client, username);
+                    let (req, _pl) = req.into_parts();
+                    let res = HttpResponse::TooManyRequests().finish();
+                    return Ok(ServiceResponse::new(req, res).map_into_right_body());
+                }
+                user = store.authenticate(username, password).await;
+                match user {
+                    Some(_) => limiter.clear(client, &limit_key),
+                    None => limiter.record_failure(client, &limit_key),
+                }
+            }
+            if user.is_none()
+                && let Some(token) = &bearer
+            {
+                user = store.token(token);
+            }
+
+            match user {
+                Some(user) if user.role.allows(required) => {
+                    debug!("{}: authenticated as {}", user.username, user.role);
+                    req.extensions_mut().insert(user);
+                }
+                Some(user) => {
+                    debug!("{}: role {} is not enough, needs {}", user.username, user.role, required);
+                }
+                // Wrong Basic credentials get a challenge instead of falling through to the whitelist
+                None if basic.is_some() => {
+                    warn!("Failed Basic authentication for {:?}", basic.map(|(username, _)| username));
+                    let (req, _pl) = req.into_parts();
+                    let res = HttpResponse::build(StatusCode::UNAUTHORIZED).insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"korvatunturi\"")).finish();
+                    return Ok(ServiceResponse::new(req, res).map_into_right_body());
+                }
+                None if bearer.is_some() => {
+                    warn!("Rejected an unknown API token");
+                    let (req, _pl) = req.into_parts();
+                    let res = HttpResponse::build(StatusCode::UNAUTHORIZED).insert_header((header::WWW_AUTHENTICATE, "Bearer realm=\"korvatunturi\"")).finish();
+                    return Ok(ServiceResponse::new(req, res).map_into_right_body());
+                }
+                None => {}
+            }
+
+            let res = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+// Set by `UserAuth`, handlers use it to find out who they are talking to
+pub fn authenticated_user(req: &HttpRequest) -> Option<AuthenticatedUser> {
+    req.extensions().get::<AuthenticatedUser>().cloned()
+}
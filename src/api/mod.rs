@@ -1,3 +1,6 @@
+pub mod limiter;
+pub mod metrics;
 pub mod middleware;
 pub mod routes;
+pub mod signing;
 pub use routes::*;
@@ -0,0 +1,105 @@
+use crate::cache::{
+    core::{FileCache, FileCacheError},
+    expiry::DurationValue,
+    listing::FileFilter,
+};
+use actix_web::{HttpResponse, web};
+use log::error;
+use serde::Deserialize;
+
+pub async fn files(cache: web::Data<FileCache>, filter: web::Query<FileFilter>) -> actix_web::Result<HttpResponse> {
+    match cache.list_files(&filter).await {
+        Ok(page) => Ok(HttpResponse::Ok().json(page)),
+        Err(FileCacheError::InvalidOptions(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(e) => {
+            error!("Error listing files: {:?}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn trash(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    match cache.list_trash().await {
+        Ok(files) => Ok(HttpResponse::Ok().json(files)),
+        Err(e) => {
+            error!("Error listing the trash: {:?}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RestoreOptions {
+    // New lifetime, expired files get the default one when left out
+    pub expires_in: Option<DurationValue>,
+}
+
+pub async fn restore(cache: web::Data<FileCache>, path: web::Path<String>, query: web::Query<RestoreOptions>) -> actix_web::Result<HttpResponse> {
+    let ttl = match query.expires_in.as_ref().map(DurationValue::to_duration).transpose() {
+        Ok(ttl) => ttl,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
+    match cache.restore_file(&path, ttl).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(FileCacheError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(FileCacheError::InvalidOptions(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(e) => {
+            error!("Error restoring {}: {:?}", path, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn purge(cache: web::Data<FileCache>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    match cache.purge_file(&path).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(FileCacheError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            error!("Error purging {}: {:?}", path, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Outcome of the last consistency check, the checker also runs at startup
+pub async fn consistency(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    match cache.last_consistency_report().await {
+        Some(report) => Ok(HttpResponse::Ok().json(report)),
+        None => Ok(HttpResponse::NotFound().body("no consistency check has finished yet")),
+    }
+}
+
+pub async fn check_consistency(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    match cache.run_consistency_check().await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(e) => {
+            error!("Error checking consistency: {:?}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Scrubber progress along with everything that has been quarantined
+pub async fn integrity(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    match cache.scrub_report().await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(e) => {
+            error!("Error reading the integrity status: {:?}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Backlog of metadata writes waiting for the background routines
+pub async fn queue(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(cache.queue_report()))
+}
+
+// Liveness of the background routines, 503 while any of them is down
+pub async fn health(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    let report = cache.task_health();
+    match report.healthy {
+        true => Ok(HttpResponse::Ok().json(report)),
+        false => Ok(HttpResponse::ServiceUnavailable().json(report)),
+    }
+}
@@ -0,0 +1,60 @@
+use crate::api::middleware::authenticated_user;
+use crate::cache::{
+    FileChanges,
+    core::{FileCache, FileCacheError},
+};
+use crate::users::Role;
+use actix_web::{HttpRequest, HttpResponse, web};
+use log::{error, info};
+
+// Handed out on upload, lets whoever uploaded a file change or delete it
+pub const DELETION_TOKEN_HEADER: &str = "X-Deletion-Token";
+
+// Admins can manage any file, everyone else needs the file's deletion token
+async fn authorize(req: &HttpRequest, cache: &FileCache, uuid: &str) -> Result<(), HttpResponse> {
+    let Some(entry) = cache.fetch_entry(uuid).await else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    if authenticated_user(req).is_some_and(|user| user.role.allows(Role::Admin)) {
+        return Ok(());
+    }
+    let token = req.headers().get(DELETION_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+    match token {
+        Some(token) if entry.verify_deletion_token(token) => Ok(()),
+        _ => Err(HttpResponse::Forbidden().finish()),
+    }
+}
+
+pub async fn modify(req: HttpRequest, cache: web::Data<FileCache>, path: web::Path<String>, changes: web::Json<FileChanges>) -> actix_web::Result<HttpResponse> {
+    let uuid = path.into_inner();
+    if let Err(res) = authorize(&req, &cache, &uuid).await {
+        return Ok(res);
+    }
+
+    let role = authenticated_user(&req).map(|user| user.role);
+    match cache.modify_file(&uuid, changes.into_inner(), role).await {
+        Ok(()) => {
+            info!("Modified {}", uuid);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(FileCacheError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(FileCacheError::InvalidOptions(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(FileCacheError::InvalidExpiry(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(e) => {
+            error!("Error modifying {}: {:?}", uuid, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn delete(req: HttpRequest, cache: web::Data<FileCache>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let uuid = path.into_inner();
+    if let Err(res) = authorize(&req, &cache, &uuid).await {
+        return Ok(res);
+    }
+
+    match cache.delete_files(std::slice::from_ref(&uuid)).await {
+        0 => Ok(HttpResponse::NotFound().finish()),
+        _ => Ok(HttpResponse::NoContent().finish()),
+    }
+}
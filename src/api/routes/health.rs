@@ -0,0 +1,65 @@
+use crate::api::metrics::Metrics;
+use crate::cache::{core::FileCache, health::ReadinessReport, manage::MemoryUsage, queue::QueueReport};
+use crate::settings::{ConfigSummary, Configuration};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::time::Instant;
+
+// Fixed at startup, shared by every worker
+pub struct ServiceInfo {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    config: ConfigSummary,
+}
+
+#[derive(Serialize)]
+struct Diagnostics<'a> {
+    version: &'static str,
+    started: DateTime<Utc>,
+    uptime_secs: u64,
+    config: &'a ConfigSummary,
+    queue: QueueReport,
+    memory: MemoryUsage,
+    readiness: ReadinessReport,
+}
+
+impl ServiceInfo {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            config: ConfigSummary::from(config),
+        }
+    }
+}
+
+// Liveness, answering at all is the point
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+// Readiness, 503 until the server can take uploads. The details are on `/api/diagnostics`
+pub async fn readyz(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    match cache.readiness().await.ready {
+        true => Ok(HttpResponse::Ok().body("ready")),
+        false => Ok(HttpResponse::ServiceUnavailable().body("not ready")),
+    }
+}
+
+pub async fn diagnostics(cache: web::Data<FileCache>, info: web::Data<ServiceInfo>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(Diagnostics {
+        version: env!("CARGO_PKG_VERSION"),
+        started: info.started_at,
+        uptime_secs: info.started.elapsed().as_secs(),
+        config: &info.config,
+        queue: cache.queue_report(),
+        memory: cache.memory_usage().await,
+        readiness: cache.readiness().await,
+    }))
+}
+
+// Prometheus scrape target
+pub async fn metrics(cache: web::Data<FileCache>, metrics: web::Data<Metrics>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4; charset=utf-8").body(metrics.render(&cache).await))
+}
@@ -1,2 +1,5 @@
+pub mod admin;
+pub mod file;
+pub mod health;
 pub mod private;
 pub mod public;
@@ -1,39 +1,158 @@
-use crate::cache::{FileOptions, core::FileCache};
+use crate::api::{
+    metrics::Metrics,
+    middleware::{ClientIp, authenticated_user},
+    routes::{file::DELETION_TOKEN_HEADER, public::PASSWORD_HEADER},
+    signing::UrlSigner,
+};
+use crate::cache::{
+    CacheEntry, FileOptions,
+    core::{FileCache, FileCacheError},
+    quota::QuotaKind,
+    scrub::ScrubSummary,
+};
 use actix_multipart::Multipart;
-use actix_web::{HttpResponse, web};
+use actix_web::{
+    HttpRequest, HttpResponse,
+    http::{StatusCode, header},
+    web,
+};
+use chrono::{SecondsFormat, Utc};
 use futures_util::StreamExt as _;
 use log::trace;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use tokio::time::Instant;
 
-pub async fn upload(cache: web::Data<FileCache>, mut query: web::Query<FileOptions>, mut payload: Multipart) -> actix_web::Result<HttpResponse> {
-    while let Some(item) = payload.next().await {
-        let max_size = cache.max_size.clone();
-        let mut field = item?;
-        let mut bytes = Vec::new();
-        let read_start = Instant::now();
-
-        while let Some(Ok(data)) = field.next().await {
-            bytes.extend(data);
-            if bytes.len() > max_size {
-                break;
-            }
+// Effective expiry of an upload, after the TTL limits and the retention curve
+const EXPIRES_AT_HEADER: &str = "X-Expires-At";
+const EXPIRES_IN_HEADER: &str = "X-Expires-In";
+// Boundaries and part headers around the file in a multipart body
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
+
+// Identifies who an upload belongs to
+fn upload_owner(req: &HttpRequest, client_ip: &ClientIp) -> String {
+    match authenticated_user(req) {
+        Some(user) => user.owner(),
+        None => match client_ip.resolve(req) {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".to_string(),
+        },
+    }
+}
+
+pub async fn upload(req: HttpRequest, cache: web::Data<FileCache>, client_ip: web::Data<ClientIp>, metrics: web::Data<Metrics>, query: web::Query<FileOptions>, payload: Multipart) -> actix_web::Result<HttpResponse> {
+    let started = Instant::now();
+    let mut size = 0;
+    let response = store(&req, &cache, &client_ip, query, payload, &mut size).await?;
+    metrics.record_upload(&response, started.elapsed(), size);
+    Ok(response)
+}
+
+// Takes the first file of the form, `size` ends up holding its length
+async fn store(req: &HttpRequest, cache: &FileCache, client_ip: &ClientIp, mut query: web::Query<FileOptions>, mut payload: Multipart, size: &mut usize) -> actix_web::Result<HttpResponse> {
+    let owner = upload_owner(req, client_ip);
+    if let Some(password) = req.headers().get(PASSWORD_HEADER).and_then(|h| h.to_str().ok()) {
+        query.password = Some(password.to_string());
+    }
+
+    // Refuse before reading the body when the client tells us how big it is. Nothing is evicted on its word, only `upload_file` does that
+    if let Some(length) = req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok()) {
+        if length > (cache.max_size + MULTIPART_OVERHEAD) as u64 {
+            return Ok(HttpResponse::PayloadTooLarge().body("file is too large"));
+        }
+        if !cache.has_room_for(length.min(cache.max_size as u64)).await {
+            return Ok(HttpResponse::InsufficientStorage().body("storage is full"));
         }
-        trace!("Entire fileread took {:#3?}", read_start.elapsed());
+    }
 
-        let upload_start = Instant::now();
-        let filename = query.filename.take().unwrap_or(field.content_disposition().map(|f| f.get_filename().unwrap_or("upload.bin")).unwrap_or("upload.bin").to_string());
-        if let Ok(uuid) = cache.upload_file(bytes, &filename, query.0).await {
-            trace!("Upload / write took {:#3?}", upload_start.elapsed());
+    // The file is the first field, anything after it is ignored
+    let Some(item) = payload.next().await else {
+        return Ok(HttpResponse::InternalServerError().finish());
+    };
+    let max_size = cache.max_size;
+    let mut field = item?;
+    let mut bytes = Vec::new();
+    let read_start = Instant::now();
+
+    while let Some(Ok(data)) = field.next().await {
+        bytes.extend(data);
+        if bytes.len() > max_size {
+            break;
+        }
+    }
+    trace!("Entire fileread took {:#3?}", read_start.elapsed());
 
-            return Ok(HttpResponse::Ok().body(uuid));
+    let upload_start = Instant::now();
+    let filename = query.filename.take().unwrap_or(field.content_disposition().map(|f| f.get_filename().unwrap_or("upload.bin")).unwrap_or("upload.bin").to_string());
+    let role = authenticated_user(req).map(|user| user.role);
+    *size = bytes.len();
+    match cache.upload_file(bytes, &filename, query.0, &owner, role).await {
+        Ok(upload) => {
+            trace!("Upload / write took {:#3?}", upload_start.elapsed());
+            // The body stays the bare uuid for existing scripts, everything else goes in headers
+            let expires_in = (upload.expiration - Utc::now()).num_seconds().max(0);
+            Ok(HttpResponse::Ok()
+                .insert_header((DELETION_TOKEN_HEADER, upload.deletion_token))
+                .insert_header((EXPIRES_AT_HEADER, upload.expiration.to_rfc3339_opts(SecondsFormat::Secs, true)))
+                .insert_header((EXPIRES_IN_HEADER, expires_in.to_string()))
+                .body(upload.uuid))
+        }
+        Err(FileCacheError::InvalidOptions(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(FileCacheError::InvalidExpiry(reason)) => Ok(HttpResponse::BadRequest().body(reason)),
+        Err(FileCacheError::NoSpaceLeftOnDevice) => Ok(HttpResponse::InsufficientStorage().body("storage is full")),
+        Err(FileCacheError::ShuttingDown) => Ok(HttpResponse::ServiceUnavailable().body("shutting down")),
+        Err(FileCacheError::QuotaExceeded(kind)) => {
+            let status = match kind {
+                QuotaKind::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                QuotaKind::DailyBytes => StatusCode::TOO_MANY_REQUESTS,
+                QuotaKind::LiveBytes | QuotaKind::LiveFiles => StatusCode::INSUFFICIENT_STORAGE,
+            };
+            Ok(HttpResponse::build(status).body(kind.to_string()))
         }
-        break;
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
     }
+}
+
+// Quota usage of whoever is asking
+pub async fn usage(req: HttpRequest, cache: web::Data<FileCache>, client_ip: web::Data<ClientIp>) -> actix_web::Result<HttpResponse> {
+    let owner = upload_owner(&req, &client_ip);
+    Ok(HttpResponse::Ok().json(cache.usage(&owner).await))
+}
+
+// Library size and free space as last seen by the monitor
+pub async fn storage(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(cache.disk_usage()))
+}
 
-    Ok(HttpResponse::InternalServerError().finish())
+#[derive(Serialize)]
+pub struct StatusReport {
+    entries: Vec<CacheEntry>,
+    // Counted by the integrity scrubber since startup
+    scrub: ScrubSummary,
 }
 
 pub async fn status(cache: web::Data<FileCache>) -> actix_web::Result<HttpResponse> {
-    let entries = cache.fetch_entries().await;
-    Ok(HttpResponse::Ok().json(entries))
+    Ok(HttpResponse::Ok().json(StatusReport {
+        entries: cache.fetch_entries().await,
+        scrub: cache.scrub_status().summary().await,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SignOptions {
+    pub expires_in: Option<u64>,
+    // Binds the URL to a client address
+    pub ip: Option<IpAddr>,
+}
+
+pub async fn sign(cache: web::Data<FileCache>, signer: web::Data<UrlSigner>, path: web::Path<String>, query: web::Query<SignOptions>) -> actix_web::Result<HttpResponse> {
+    let file = path.into_inner();
+    if cache.fetch_entry(&file).await.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    match signer.sign(&file, query.expires_in, query.ip) {
+        Some(signature) => Ok(HttpResponse::Ok().body(signer.link(&file, &signature))),
+        None => Ok(HttpResponse::ServiceUnavailable().body("No signing keys configured")),
+    }
 }
@@ -1,26 +1,261 @@
-use crate::cache::{FileContent, core::FileCache};
-use actix_web::{HttpResponse, http::header, web};
+use crate::api::{
+    limiter::AttemptLimiter,
+    metrics::Metrics,
+    middleware::ClientIp,
+    signing::{SignedQuery, UrlSigner},
+};
+use crate::cache::{
+    FileContent,
+    core::{FileCache, FileCacheError},
+};
+use crate::frontend::{NotYetAvailable, PasswordPrompt};
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use askama::Template;
+use chrono::{DateTime, Utc};
 use futures_util::TryStreamExt;
-use tokio_util::io::ReaderStream;
+use log::{error, warn};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+// Also how uploads set a password, it never belongs in a URL the access log prints
+pub const PASSWORD_HEADER: &str = "X-File-Password";
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    pub password: String,
+}
+
+fn wants_html(req: &HttpRequest) -> bool {
+    req.headers().get(header::ACCEPT).and_then(|h| h.to_str().ok()).map(|h| h.contains("text/html")).unwrap_or(false)
+}
+
+fn password_prompt(req: &HttpRequest, failed: bool) -> HttpResponse {
+    // API clients get a bare 401, browsers get a form
+    if !wants_html(req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let server_name = req.app_data::<web::Data<(String, String)>>().map(|d| d.0.clone()).unwrap_or_default();
+    let action = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), query),
+    };
+
+    let page = PasswordPrompt {
+        server_name: &server_name,
+        action: &action,
+        failed,
+    };
+    match page.render() {
+        Ok(page) => HttpResponse::Unauthorized().content_type("text/html; charset=utf-8").body(page),
+        Err(e) => {
+            error!("error templating: {}", e);
+            HttpResponse::InternalServerError().body("Error templating password page")
+        }
+    }
+}
+
+// Scheduled files are a plain 404 to API clients, browsers are told when to come back
+fn not_yet_available(req: &HttpRequest, available_from: DateTime<Utc>) -> HttpResponse {
+    if !wants_html(req) {
+        return HttpResponse::NotFound().finish();
+    }
+    let server_name = req.app_data::<web::Data<(String, String)>>().map(|d| d.0.clone()).unwrap_or_default();
+    let available_from = available_from.format("%Y-%m-%d %H:%M UTC").to_string();
+    let page = NotYetAvailable {
+        server_name: &server_name,
+        available_from: &available_from,
+    };
+    match page.render() {
+        Ok(page) => HttpResponse::NotFound().content_type("text/html; charset=utf-8").body(page),
+        Err(e) => {
+            error!("error templating: {}", e);
+            HttpResponse::InternalServerError().body("Error templating availability page")
+        }
+    }
+}
+
+pub async fn download(req: HttpRequest, cache: web::Data<FileCache>, metrics: web::Data<Metrics>, path: web::Path<String>, signature: web::Query<SignedQuery>) -> actix_web::Result<HttpResponse> {
+    let started = Instant::now();
+    let password = req.headers().get(PASSWORD_HEADER).and_then(|h| h.to_str().ok()).map(|h| h.to_string());
+    let response = serve(req, cache, path.into_inner(), signature.into_inner(), password).await?;
+    metrics.record_download(&response, started.elapsed());
+    Ok(response)
+}
+
+pub async fn download_form(req: HttpRequest, cache: web::Data<FileCache>, metrics: web::Data<Metrics>, path: web::Path<String>, signature: web::Query<SignedQuery>, form: web::Form<PasswordForm>) -> actix_web::Result<HttpResponse> {
+    let started = Instant::now();
+    let response = serve(req, cache, path.into_inner(), signature.into_inner(), Some(form.into_inner().password)).await?;
+    metrics.record_download(&response, started.elapsed());
+    Ok(response)
+}
+
+async fn serve(req: HttpRequest, cache: web::Data<FileCache>, file: String, signature: SignedQuery, password: Option<String>) -> actix_web::Result<HttpResponse> {
+    let (Some(signer), Some(limiter), Some(client_ip)) = (req.app_data::<web::Data<UrlSigner>>(), req.app_data::<web::Data<AttemptLimiter>>(), req.app_data::<web::Data<ClientIp>>()) else {
+        error!("Download route is missing its app data");
+        return Ok(HttpResponse::InternalServerError().finish());
+    };
+
+    // Access checks happen before fetch_file so failed attempts never count as reads
+    if let Some(entry) = cache.fetch_entry(&file).await {
+        let client = client_ip.resolve(&req);
+
+        if let Some(available_from) = entry.available_from() {
+            return Ok(not_yet_available(&req, available_from));
+        }
+
+        // Private files need a valid signature, without one they don't exist as far as the client knows
+        if entry.is_private() {
+            if !signature.is_present() {
+                return Ok(HttpResponse::NotFound().finish());
+            }
+            if !signer.verify(&file, &signature, client) {
+                return Ok(HttpResponse::Forbidden().finish());
+            }
+        }
+
+        if entry.is_password_protected() {
+            let Some(password) = password else {
+                return Ok(password_prompt(&req, false));
+            };
+            if limiter.is_limited(client, &file) {
+                return Ok(HttpResponse::TooManyRequests().finish());
+            }
+
+            let valid = web::block(move || entry.verify_password(&password)).await.unwrap_or(false);
+            if !valid {
+                warn!("{:?}: wrong password for {}", client, file);
+                limiter.record_failure(client, &file);
+                return Ok(password_prompt(&req, true));
+            }
+            limiter.clear(client, &file);
+        }
+    }
+
+    let fetched = cache.fetch_file(&file).await;
+    if let Err(FileCacheError::NotYetAvailable(available_from)) = fetched {
+        return Ok(not_yet_available(&req, available_from));
+    }
+    if let Ok((filename, data)) = fetched {
+        // End-to-end encrypted files have no name on the server
+        let filename = if filename.is_empty() { format!("{}.bin", file) } else { filename };
+        let len = match &data {
+            FileContent::InMemory(bytes) => bytes.len() as u64,
+            FileContent::OnDisk(reader) => reader.len(),
+        };
+        let (mut response, range) = match requested_range(&req, len) {
+            Ok(Some((start, end))) => {
+                let mut response = HttpResponse::PartialContent();
+                response.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, len)));
+                (response, (start, end))
+            }
+            Ok(None) => (HttpResponse::Ok(), (0, len)),
+            Err(_) => {
+                return Ok(HttpResponse::RangeNotSatisfiable().insert_header((header::CONTENT_RANGE, format!("bytes */{}", len))).finish());
+            }
+        };
+        response
+            .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .content_type("application/octet-stream");
 
-pub async fn download(cache: web::Data<FileCache>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
-    let file = path.into_inner();
-    if let Ok((filename, data)) = cache.fetch_file(&file).await {
         match data {
             FileContent::InMemory(bytes) => {
-                return Ok(HttpResponse::Ok()
-                    .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
-                    .content_type("application/octet-stream")
-                    .body(bytes));
+                return Ok(response.body(bytes.slice(range.0 as usize..range.1 as usize)));
             }
             FileContent::OnDisk(reader) => {
-                let stream = ReaderStream::new(reader).map_err(actix_web::error::ErrorInternalServerError);
-                return Ok(HttpResponse::Ok()
-                    .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
-                    .content_type("application/octet-stream")
-                    .streaming(stream));
+                let stream = reader.into_stream(range.0, range.1).map_err(actix_web::error::ErrorInternalServerError);
+                return Ok(response.no_chunking(range.1 - range.0).streaming(stream));
             }
         }
     }
     return Ok(HttpResponse::NotFound().finish());
 }
+
+// Encrypted metadata of an end-to-end upload, fetching it doesn't count as a read
+pub async fn metadata(req: HttpRequest, cache: web::Data<FileCache>, signer: web::Data<UrlSigner>, client_ip: web::Data<ClientIp>, path: web::Path<String>, signature: web::Query<SignedQuery>) -> actix_web::Result<HttpResponse> {
+    let file = path.into_inner();
+    let Some(entry) = cache.fetch_entry(&file).await else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    if entry.available_from().is_some() || entry.is_private() && !signer.verify(&file, &signature, client_ip.resolve(&req)) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    match entry.metadata() {
+        Some(metadata) if entry.is_e2e() => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "metadata": metadata,
+            "password_protected": entry.is_password_protected(),
+        }))),
+        _ => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Only a single `bytes=` range is supported, anything fancier gets the whole file
+fn requested_range(req: &HttpRequest, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(raw) = req.headers().get(header::RANGE).and_then(|h| h.to_str().ok()) else {
+        return Ok(None);
+    };
+    let Some(spec) = raw.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.trim().parse::<u64>().ok(), end.trim().parse::<u64>().ok()) {
+        // bytes=a-b
+        (Some(start), Some(end)) if start <= end => (start, (end + 1).min(len)),
+        // bytes=a-
+        (Some(start), None) if end.trim().is_empty() => (start, len),
+        // bytes=-n
+        (None, Some(suffix)) if start.trim().is_empty() && suffix > 0 => (len.saturating_sub(suffix), len),
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+        requested_range(&TestRequest::default().insert_header((header::RANGE, value)).to_http_request(), len)
+    }
+
+    #[test]
+    fn no_header_means_the_whole_file() {
+        assert_eq!(requested_range(&TestRequest::default().to_http_request(), 100), Ok(None));
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range("bytes=0-9", 100), Ok(Some((0, 10))));
+        assert_eq!(range("bytes=90-", 100), Ok(Some((90, 100))));
+        assert_eq!(range("bytes=-10", 100), Ok(Some((90, 100))));
+        // Past the end is clamped
+        assert_eq!(range("bytes=50-500", 100), Ok(Some((50, 100))));
+        assert_eq!(range("bytes=-500", 100), Ok(Some((0, 100))));
+    }
+
+    #[test]
+    fn ignores_what_it_doesnt_support() {
+        assert_eq!(range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(range("items=0-9", 100), Ok(None));
+        assert_eq!(range("bytes=9-0", 100), Ok(None));
+        assert_eq!(range("bytes=-0", 100), Ok(None));
+        assert_eq!(range("bytes=abc", 100), Ok(None));
+    }
+
+    #[test]
+    fn rejects_ranges_starting_past_the_end() {
+        assert_eq!(range("bytes=100-", 100), Err(()));
+        assert_eq!(range("bytes=200-300", 100), Err(()));
+    }
+}
@@ -0,0 +1,223 @@
+use crate::settings::SigningConfig;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{collections::HashMap, net::IpAddr};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Query parameters carried by a signed download URL
+#[derive(Deserialize)]
+pub struct SignedQuery {
+    pub expires: Option<i64>,
+    pub kid: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub sig: Option<String>,
+}
+
+impl SignedQuery {
+    pub fn is_present(&self) -> bool {
+        self.sig.is_some()
+    }
+}
+
+pub struct UrlSigner {
+    // key id -> secret
+    keys: HashMap<String, Vec<u8>>,
+    active: Option<String>,
+    default_ttl: u64,
+    max_ttl: u64,
+    public_url: Option<String>,
+}
+
+impl UrlSigner {
+    pub fn new(conf: &SigningConfig) -> Self {
+        let mut keys = HashMap::new();
+        for key in &conf.keys {
+            if key.secret.len() < 32 {
+                warn!("Signing key {} is shorter than 32 bytes, consider rotating it", key.id);
+            }
+            keys.insert(key.id.clone(), key.secret.as_bytes().to_vec());
+        }
+
+        let active = match &conf.active_key {
+            Some(id) if keys.contains_key(id) => Some(id.clone()),
+            Some(id) => {
+                warn!("Active signing key {} is not configured, falling back to the first key", id);
+                conf.keys.first().map(|k| k.id.clone())
+            }
+            None => conf.keys.first().map(|k| k.id.clone()),
+        };
+        if active.is_none() {
+            warn!("No signing keys configured, private files can't be downloaded");
+        }
+
+        Self {
+            keys,
+            active,
+            default_ttl: conf.default_ttl as u64,
+            max_ttl: conf.max_ttl as u64,
+            public_url: conf.public_url.as_ref().map(|url| url.trim_end_matches('/').to_string()),
+        }
+    }
+
+    fn mac(secret: &[u8], uuid: &str, expires: i64, ip: Option<&IpAddr>) -> HmacSha256 {
+        // HMAC accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC can take a key of any size");
+        let ip = ip.map(|ip| ip.to_string()).unwrap_or_default();
+        mac.update(format!("{}\n{}\n{}", uuid, expires, ip).as_bytes());
+        mac
+    }
+
+    /// Returns the query string for a signed URL, or None if there's no key to sign with
+    pub fn sign(&self, uuid: &str, ttl: Option<u64>, ip: Option<IpAddr>) -> Option<String> {
+        let kid = self.active.as_ref()?;
+        let secret = self.keys.get(kid)?;
+
+        let ttl = ttl.unwrap_or(self.default_ttl).min(self.max_ttl);
+        let expires = Utc::now().timestamp() + ttl as i64;
+        let sig = hex::encode(Self::mac(secret, uuid, expires, ip.as_ref()).finalize().into_bytes());
+
+        let mut query = format!("expires={}&kid={}&sig={}", expires, kid, sig);
+        if let Some(ip) = ip {
+            query.push_str(&format!("&ip={}", ip));
+        }
+        Some(query)
+    }
+
+    /// Download link for a signed query, never derived from the request so a forged Host can't redirect it
+    pub fn link(&self, uuid: &str, query: &str) -> String {
+        format!("{}/api/download/{}?{}", self.public_url.as_deref().unwrap_or_default(), uuid, query)
+    }
+
+    pub fn verify(&self, uuid: &str, query: &SignedQuery, client: Option<IpAddr>) -> bool {
+        let (Some(expires), Some(kid), Some(sig)) = (query.expires, &query.kid, &query.sig) else {
+            return false;
+        };
+        if expires < Utc::now().timestamp() {
+            return false;
+        }
+        // IP bound URLs only work for the address they were minted for
+        if query.ip.is_some() && client != query.ip {
+            return false;
+        }
+        let (Some(secret), Ok(sig)) = (self.keys.get(kid), hex::decode(sig)) else {
+            return false;
+        };
+
+        // Constant time comparison
+        Self::mac(secret, uuid, expires, query.ip.as_ref()).verify_slice(&sig).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SigningKey;
+    use actix_web::web::Query;
+
+    const UUID: &str = "6c31346f-0cad-4fa3-94cc-f994b741902b";
+
+    fn signer(keys: &[&str], active: Option<&str>) -> UrlSigner {
+        UrlSigner::new(&SigningConfig {
+            keys: keys
+                .iter()
+                .map(|id| SigningKey {
+                    id: id.to_string(),
+                    secret: format!("{}-0123456789abcdef0123456789abcdef", id),
+                })
+                .collect(),
+            active_key: active.map(str::to_string),
+            ..SigningConfig::default()
+        })
+    }
+
+    fn parse(query: &str) -> SignedQuery {
+        Query::<SignedQuery>::from_query(query).unwrap().into_inner()
+    }
+
+    #[test]
+    fn verifies_its_own_signatures() {
+        let signer = signer(&["k1"], None);
+        let query = parse(&signer.sign(UUID, None, None).unwrap());
+        assert!(query.is_present());
+        assert!(signer.verify(UUID, &query, None));
+        assert!(signer.verify(UUID, &query, "10.0.0.1".parse().ok()));
+        // Bound to the file it was minted for
+        assert!(!signer.verify("another-file", &query, None));
+    }
+
+    #[test]
+    fn rejects_expired_and_tampered_urls() {
+        let signer = signer(&["k1"], None);
+        let signed = |expires: i64| {
+            let sig = hex::encode(UrlSigner::mac(b"k1-0123456789abcdef0123456789abcdef", UUID, expires, None).finalize().into_bytes());
+            parse(&format!("expires={}&kid=k1&sig={}", expires, sig))
+        };
+        assert!(signer.verify(UUID, &signed(Utc::now().timestamp() + 60), None));
+        assert!(!signer.verify(UUID, &signed(Utc::now().timestamp() - 1), None));
+
+        let mut query = parse(&signer.sign(UUID, None, None).unwrap());
+        query.expires = query.expires.map(|expires| expires + 3600);
+        assert!(!signer.verify(UUID, &query, None));
+        assert!(!signer.verify(UUID, &parse("expires=99999999999&kid=k1&sig=zz"), None));
+        assert!(!signer.verify(UUID, &parse(""), None));
+    }
+
+    #[test]
+    fn caps_the_lifetime() {
+        let signer = signer(&["k1"], None);
+        let query = parse(&signer.sign(UUID, Some(u64::MAX / 2), None).unwrap());
+        assert!(query.expires.unwrap() <= Utc::now().timestamp() + signer.max_ttl as i64);
+    }
+
+    #[test]
+    fn ip_bound_urls_only_work_for_that_address() {
+        let signer = signer(&["k1"], None);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let query = parse(&signer.sign(UUID, None, Some(ip)).unwrap());
+        assert!(signer.verify(UUID, &query, Some(ip)));
+        assert!(!signer.verify(UUID, &query, "10.0.0.2".parse().ok()));
+        assert!(!signer.verify(UUID, &query, None));
+
+        // Dropping the binding breaks the signature
+        let mut unbound = parse(&signer.sign(UUID, None, Some(ip)).unwrap());
+        unbound.ip = None;
+        assert!(!signer.verify(UUID, &unbound, None));
+    }
+
+    #[test]
+    fn old_keys_verify_until_they_are_removed() {
+        let before = signer(&["k1"], None);
+        let query = parse(&before.sign(UUID, None, None).unwrap());
+
+        let rotating = signer(&["k1", "k2"], Some("k2"));
+        assert!(rotating.verify(UUID, &query, None));
+        let fresh = parse(&rotating.sign(UUID, None, None).unwrap());
+        assert_eq!(fresh.kid.as_deref(), Some("k2"));
+        assert!(!before.verify(UUID, &fresh, None));
+
+        let after = signer(&["k2"], None);
+        assert!(!after.verify(UUID, &query, None));
+        assert!(after.verify(UUID, &fresh, None));
+    }
+
+    #[test]
+    fn falls_back_to_the_first_key_and_signs_nothing_without_keys() {
+        let signer_with_unknown_active = signer(&["k1", "k2"], Some("k3"));
+        assert_eq!(parse(&signer_with_unknown_active.sign(UUID, None, None).unwrap()).kid.as_deref(), Some("k1"));
+        assert!(signer(&[], None).sign(UUID, None, None).is_none());
+    }
+
+    #[test]
+    fn links_use_the_configured_origin_only() {
+        assert_eq!(signer(&["k1"], None).link(UUID, "sig=1"), format!("/api/download/{}?sig=1", UUID));
+        let signer = UrlSigner::new(&SigningConfig {
+            public_url: Some("https://box.example.org/".to_string()),
+            ..SigningConfig::default()
+        });
+        assert_eq!(signer.link(UUID, "sig=1"), format!("https://box.example.org/api/download/{}?sig=1", UUID));
+    }
+}
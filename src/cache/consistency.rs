@@ -0,0 +1,331 @@
+use crate::{cache::mem::CacheMemory, flush_entry};
+
+use super::{
+    core::{FileCache, FileCacheError},
+    crypto,
+    entry::CacheEntry,
+    library::is_blob_name,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs::{create_dir_all, read_dir, rename};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+// Orphaned blobs are moved here instead of being deleted
+const QUARANTINE_DIR: &str = "quarantine";
+// Blobs this fresh may belong to an upload that hasn't reached the database yet
+const MIN_BLOB_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepairPolicy {
+    // Remove whatever is broken
+    Drop,
+    // Take it out of service but keep it around for inspection
+    #[default]
+    Quarantine,
+}
+
+#[derive(FromRow)]
+struct StoredRow {
+    uuid: String,
+    size_bytes: Option<i64>,
+    encrypted: i8,
+    trashed: i8,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SizeMismatch {
+    pub id: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ConsistencyReport {
+    pub checked_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub policy: RepairPolicy,
+    pub rows_checked: usize,
+    pub blobs_checked: usize,
+    // Rows whose blob is gone
+    pub missing_blobs: Vec<String>,
+    // Blobs no row refers to
+    pub orphaned_blobs: Vec<String>,
+    pub size_mismatches: Vec<SizeMismatch>,
+}
+
+#[derive(FromRow)]
+struct QuarantinedRow {
+    uuid: String,
+    quarantined_utc: DateTime<Utc>,
+    quarantine_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct QuarantinedFile {
+    pub id: String,
+    pub quarantined: DateTime<Utc>,
+    pub reason: Option<String>,
+}
+
+impl ConsistencyReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_blobs.is_empty() && self.orphaned_blobs.is_empty() && self.size_mismatches.is_empty()
+    }
+}
+
+// Uuid -> size on disk of every blob in the library old enough to judge
+async fn list_blobs(library: &Path) -> Result<HashMap<String, u64>, std::io::Error> {
+    let mut blobs = HashMap::new();
+    let mut paths = read_dir(library).await?;
+    while let Some(file) = paths.next_entry().await? {
+        let Some(filename) = file.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let metadata = file.metadata().await?;
+        if !is_blob_name(&filename) || !metadata.is_file() {
+            continue;
+        }
+        let age = metadata.modified().ok().and_then(|modified| SystemTime::now().duration_since(modified).ok()).unwrap_or_default();
+        if age >= MIN_BLOB_AGE {
+            blobs.insert(filename, metadata.len());
+        }
+    }
+    Ok(blobs)
+}
+
+/// Consistency checks
+impl FileCache {
+    /// Takes a row out of service, its blob stays where it is
+    pub(super) async fn quarantine_row(pool: &sqlx::Pool<sqlx::Sqlite>, uuid: &str, reason: &str) -> Result<(), sqlx::Error> {
+        warn!("Quarantining {}: {}", uuid, reason);
+        sqlx::query("UPDATE cache SET quarantined_utc = ?, quarantine_reason = ?, deleted_utc = NULL WHERE uuid = ?")
+            .bind(Utc::now())
+            .bind(reason)
+            .bind(uuid)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Download path: the entry is useless without its blob, so it goes right away
+    pub(super) async fn forget_missing(&self, uuid: &str) {
+        error!("Blob of {} is missing despite the entry being present in the database, dropping it", uuid);
+        if let Some(mut entry) = self.cache.write().await.remove(uuid) {
+            flush_entry!(entry, uuid, Duration::ZERO, self.cache_mem);
+        }
+        if let Err(e) = Self::delete_from_db(&self.pool, uuid).await {
+            error!("Failed to delete {} from the database: {}", uuid, e);
+        }
+    }
+
+    /// Compares the database with the library and repairs whatever doesn't line up. A dry run only reports, and orphans are only reported unless the library is ours
+    pub(super) async fn check_consistency(
+        cache: &RwLock<HashMap<String, CacheEntry>>,
+        cache_mem: &RwLock<CacheMemory>,
+        library: &Path,
+        pool: &sqlx::Pool<sqlx::Sqlite>,
+        policy: RepairPolicy,
+        owned_library: bool,
+        dry_run: bool,
+    ) -> Result<ConsistencyReport, FileCacheError> {
+        let started = Instant::now();
+        let rows: Vec<StoredRow> = sqlx::query_as(
+            r#"
+            SELECT uuid, size_bytes, data_key IS NOT NULL AS encrypted, deleted_utc IS NOT NULL AS trashed
+            FROM cache
+            WHERE quarantined_utc IS NULL
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(FileCacheError::DbError)?;
+        let quarantined: HashSet<String> = sqlx::query_as::<_, (String,)>("SELECT uuid FROM cache WHERE quarantined_utc IS NOT NULL")
+            .fetch_all(pool)
+            .await
+            .map_err(FileCacheError::DbError)?
+            .into_iter()
+            .map(|(uuid,)| uuid)
+            .collect();
+        let blobs = list_blobs(library).await.map_err(FileCacheError::IoError)?;
+
+        let mut report = ConsistencyReport {
+            checked_at: Utc::now(),
+            duration_ms: 0,
+            policy,
+            rows_checked: rows.len(),
+            blobs_checked: blobs.len(),
+            missing_blobs: Vec::new(),
+            orphaned_blobs: Vec::new(),
+            size_mismatches: Vec::new(),
+        };
+
+        // Entries still waiting for their row count as known
+        let known: HashSet<String> = {
+            let lock = cache.read().await;
+            rows.iter().map(|row| row.uuid.clone()).chain(lock.keys().cloned()).chain(quarantined).collect()
+        };
+
+        for row in &rows {
+            let Some(actual) = blobs.get(&row.uuid).copied() else {
+                // Fresh blobs aren't listed, only call it missing when it really isn't there
+                if tokio::fs::try_exists(library.join(&row.uuid)).await.unwrap_or(true) {
+                    continue;
+                }
+                report.missing_blobs.push(row.uuid.clone());
+                continue;
+            };
+            // Rows from older versions don't know their exact size
+            let Some(expected) = row.size_bytes.map(|size| size.max(0) as u64) else {
+                continue;
+            };
+            let stored = if row.encrypted == 1 { crypto::plaintext_len(actual) } else { actual };
+            if stored != expected {
+                report.size_mismatches.push(SizeMismatch { id: row.uuid.clone(), expected, actual: stored });
+            }
+        }
+        report.orphaned_blobs = blobs.keys().filter(|uuid| !known.contains(*uuid)).cloned().collect();
+
+        if !report.is_clean() {
+            warn!(
+                "Consistency check found {} missing blobs, {} orphaned blobs and {} size mismatches, applying {:?}",
+                report.missing_blobs.len(),
+                report.orphaned_blobs.len(),
+                report.size_mismatches.len(),
+                policy
+            );
+        }
+
+        // Nothing is touched in a dry run, rows included
+        let (missing, mismatches): (&[String], &[SizeMismatch]) = if dry_run {
+            for uuid in &report.missing_blobs {
+                info!("Would repair missing blob of {} ({:?})", uuid, policy);
+            }
+            for mismatch in &report.size_mismatches {
+                info!("Would repair size mismatch of {} ({:?})", mismatch.id, policy);
+            }
+            (&[], &[])
+        } else {
+            (&report.missing_blobs, &report.size_mismatches)
+        };
+
+        // None of these can be served anymore
+        {
+            let mut lock = cache.write().await;
+            for uuid in missing.iter().chain(mismatches.iter().map(|mismatch| &mismatch.id)) {
+                if let Some(mut entry) = lock.remove(uuid) {
+                    flush_entry!(entry, uuid, Duration::ZERO, cache_mem);
+                }
+            }
+        }
+
+        let trashed: HashSet<&str> = rows.iter().filter(|row| row.trashed == 1).map(|row| row.uuid.as_str()).collect();
+        for uuid in missing {
+            // A trashed row without its blob has nothing left to restore
+            let result = match policy {
+                RepairPolicy::Quarantine if !trashed.contains(uuid.as_str()) => Self::quarantine_row(pool, uuid, "blob is missing").await,
+                _ => Self::delete_from_db(pool, uuid).await,
+            };
+            if let Err(e) = result {
+                error!("Failed to repair {}: {}", uuid, e);
+            }
+        }
+        for mismatch in mismatches {
+            // Only the row is touched in a library that isn't ours
+            let result = match policy {
+                RepairPolicy::Drop if owned_library => Self::drop_item(&mismatch.id, library, pool).await,
+                _ => Self::quarantine_row(pool, &mismatch.id, &format!("blob holds {} bytes, expected {}", mismatch.actual, mismatch.expected))
+                    .await
+                    .map_err(FileCacheError::DbError),
+            };
+            if let Err(e) = result {
+                error!("Failed to repair {}: {:?}", mismatch.id, e);
+            }
+        }
+        // An empty database more likely means it was lost, the blobs wait for `rebuild-index`
+        let orphans: &[String] = if report.orphaned_blobs.is_empty() {
+            &[]
+        } else if !owned_library {
+            // Nothing is touched in a directory without the marker
+            warn!("The library isn't marked as ours, leaving {} unindexed files alone", report.orphaned_blobs.len());
+            &[]
+        } else if rows.is_empty() && known.is_empty() {
+            warn!("The database is empty, leaving {} unindexed blobs alone", report.orphaned_blobs.len());
+            &[]
+        } else {
+            &report.orphaned_blobs
+        };
+        for uuid in orphans {
+            // Left for `rebuild-index`, the database may have been lost
+            if Self::is_restorable(library, uuid).await {
+                warn!("Orphaned blob {} still has a sidecar, leaving it for `rebuild-index`", uuid);
+                continue;
+            }
+            if dry_run {
+                info!("Would repair orphaned blob {} ({:?})", uuid, policy);
+                continue;
+            }
+            let result = match policy {
+                RepairPolicy::Quarantine => {
+                    let quarantine = library.join(QUARANTINE_DIR);
+                    match create_dir_all(&quarantine).await {
+                        // The sidecar stays with its blob
+                        Ok(()) => match rename(library.join(uuid), quarantine.join(uuid)).await {
+                            Ok(()) => Self::move_sidecar(library, uuid, &quarantine).await,
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    }
+                }
+                // An expired sidecar describes a row that doesn't exist
+                RepairPolicy::Drop => match Self::delete_file(library, uuid).await {
+                    Ok(()) => Self::remove_sidecar(library, uuid).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = result {
+                error!("Failed to repair orphaned blob {}: {}", uuid, e);
+            }
+        }
+
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        debug!("Consistency check took {} ms", report.duration_ms);
+        if report.is_clean() {
+            info!("Consistency check passed, {} rows and {} blobs", report.rows_checked, report.blobs_checked);
+        }
+        Ok(report)
+    }
+
+    /// Runs a check right away, the result also becomes the last report
+    pub async fn run_consistency_check(&self) -> Result<ConsistencyReport, FileCacheError> {
+        let report = Self::check_consistency(&self.cache, &self.cache_mem, &self.library, &self.pool, self.cache_settings.repair_policy, self.owned_library, self.cache_settings.cleanup_dry_run).await?;
+        *self.consistency.lock().await = Some(report.clone());
+        Ok(report)
+    }
+
+    pub async fn last_consistency_report(&self) -> Option<ConsistencyReport> {
+        self.consistency.lock().await.clone()
+    }
+
+    pub async fn list_quarantine(&self) -> Result<Vec<QuarantinedFile>, FileCacheError> {
+        let rows: Vec<QuarantinedRow> = sqlx::query_as("SELECT uuid, quarantined_utc, quarantine_reason FROM cache WHERE quarantined_utc IS NOT NULL ORDER BY quarantined_utc DESC")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| QuarantinedFile {
+                id: row.uuid,
+                quarantined: row.quarantined_utc,
+                reason: row.quarantine_reason,
+            })
+            .collect())
+    }
+}
@@ -1,18 +1,30 @@
 use crate::{cache::mem::CacheMemory, flush_entry};
 
 use super::{
-    entry::{CacheEntry, CacheEntryRow},
+    consistency::ConsistencyReport,
+    crypto::CryptoError,
+    disk::DiskStatus,
+    entry::{CacheEntry, CacheEntryRow, ENTRY_COLUMNS},
+    health::LibraryProbe,
+    queue::{QUEUE_CAPACITY, QueueStats, Signal, collect_batch},
+    quota::{DailyUsage, QuotaKind},
+    scrub::ScrubStatus,
     settings::CacheSettings,
+    stats::CacheCounters,
+    supervisor::{Background, TaskHealth, TaskRegistry},
 };
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, trace, warn};
-use sqlx::sqlite::SqlitePoolOptions;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
-use tokio::time::interval;
-use tokio::{
-    fs::read_dir,
-    sync::{RwLock, mpsc},
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
 };
-use tokio::{select, time::Interval};
+use tokio::select;
+use tokio::sync::{Mutex, RwLock, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 pub(super) enum SignalAction {
     Delete,
@@ -26,6 +38,19 @@ pub enum FileCacheError {
     NotFound,
     BackingFileMissing,
     NoSpaceLeftOnDevice,
+    PasswordHashing,
+    #[allow(unused)]
+    InvalidOptions(&'static str),
+    #[allow(unused)]
+    InvalidExpiry(String),
+    KeyUnavailable,
+    ShuttingDown,
+    // Scheduled upload whose publication time hasn't come yet
+    NotYetAvailable(DateTime<Utc>),
+    #[allow(unused)]
+    QuotaExceeded(QuotaKind),
+    #[allow(unused)]
+    Encryption(CryptoError),
     #[allow(unused)]
     IoError(std::io::Error),
     #[allow(unused)]
@@ -35,18 +60,42 @@ pub enum FileCacheError {
 pub struct FileCache {
     // Path of the file storage
     pub(super) library: PathBuf,
+    // Carries the marker, see `prepare_library`
+    pub(super) owned_library: bool,
     // UUID -> CacheEntry
     pub(super) cache: Arc<RwLock<HashMap<String, CacheEntry>>>,
-    pub(super) sync: mpsc::Sender<(String, SignalAction)>,
+    pub(super) sync: mpsc::Sender<Signal>,
     pub(super) cache_settings: CacheSettings,
+    pub(super) pool: sqlx::Pool<sqlx::Sqlite>,
+    pub(super) daily_usage: Mutex<DailyUsage>,
+    pub(super) disk: Arc<DiskStatus>,
+    // Outcome of the last consistency check
+    pub(super) consistency: Arc<Mutex<Option<ConsistencyReport>>>,
+    pub(super) scrub: Arc<ScrubStatus>,
+    pub(super) queue: Arc<QueueStats>,
+    // Last write check of the library, shared by all readiness probes
+    pub(super) library_probe: LibraryProbe,
+    // Stops the background routines, see `shutdown`
+    pub(super) shutdown: CancellationToken,
+    pub(super) tasks: Mutex<Vec<JoinHandle<()>>>,
+    pub(super) supervisor: Arc<TaskRegistry>,
+    pub(super) counters: Arc<CacheCounters>,
     pub max_size: usize,
     pub cache_mem: Arc<RwLock<CacheMemory>>,
 }
 
 impl FileCache {
-    pub async fn new(cache_settings: CacheSettings, library_path: &str) -> Result<Self, sqlx::Error> {
-        let pool = SqlitePoolOptions::new().max_connections(1).connect(&cache_settings.database_path).await?;
+    async fn ensure_column(pool: &sqlx::Pool<sqlx::Sqlite>, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+        let exists: Option<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info('cache') WHERE name = ?").bind(column).fetch_optional(pool).await?;
+        if exists.is_none() {
+            info!("Migrating database: adding column {}", column);
+            sqlx::query(&format!("ALTER TABLE cache ADD COLUMN {} {}", column, definition)).execute(pool).await?;
+        }
+        Ok(())
+    }
 
+    /// Creates the cache table and adds whatever columns older versions lacked
+    pub(super) async fn init_schema(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS cache (
@@ -55,170 +104,351 @@ impl FileCache {
                 expiration_utc TEXT NOT NULL,
                 burn_after_read INTEGER NOT NULL,
                 read_count INTEGER NOT NULL,
-                file_size INTEGER NOT NULL
+                file_size INTEGER NOT NULL,
+                private INTEGER NOT NULL DEFAULT 0,
+                password_hash TEXT,
+                data_key TEXT,
+                e2e INTEGER NOT NULL DEFAULT 0,
+                metadata TEXT,
+                owner TEXT,
+                size_bytes INTEGER,
+                created_utc TEXT,
+                deletion_token TEXT,
+                max_downloads INTEGER,
+                renew_until_utc TEXT,
+                available_from_utc TEXT,
+                deleted_utc TEXT,
+                quarantined_utc TEXT,
+                quarantine_reason TEXT,
+                blob_sha256 TEXT,
+                scrubbed_utc TEXT
             )
         "#,
         )
-        .execute(&pool)
+        .execute(pool)
         .await?;
+
+        // Databases created by older versions lack the newer columns
+        Self::ensure_column(pool, "private", "INTEGER NOT NULL DEFAULT 0").await?;
+        Self::ensure_column(pool, "password_hash", "TEXT").await?;
+        Self::ensure_column(pool, "data_key", "TEXT").await?;
+        Self::ensure_column(pool, "e2e", "INTEGER NOT NULL DEFAULT 0").await?;
+        Self::ensure_column(pool, "metadata", "TEXT").await?;
+        Self::ensure_column(pool, "owner", "TEXT").await?;
+        Self::ensure_column(pool, "size_bytes", "INTEGER").await?;
+        Self::ensure_column(pool, "created_utc", "TEXT").await?;
+        Self::ensure_column(pool, "deletion_token", "TEXT").await?;
+        Self::ensure_column(pool, "max_downloads", "INTEGER").await?;
+        Self::ensure_column(pool, "renew_until_utc", "TEXT").await?;
+        Self::ensure_column(pool, "available_from_utc", "TEXT").await?;
+        Self::ensure_column(pool, "deleted_utc", "TEXT").await?;
+        Self::ensure_column(pool, "quarantined_utc", "TEXT").await?;
+        Self::ensure_column(pool, "quarantine_reason", "TEXT").await?;
+        Self::ensure_column(pool, "blob_sha256", "TEXT").await?;
+        Self::ensure_column(pool, "scrubbed_utc", "TEXT").await?;
+        Ok(())
+    }
+
+    /// Re-wraps every data key with the current master key, returns the number of rewrapped entries
+    pub async fn rotate_keys(cache_settings: &CacheSettings) -> Result<usize, FileCacheError> {
+        let Some(keyring) = &cache_settings.keyring else {
+            return Err(FileCacheError::KeyUnavailable);
+        };
+        let pool = Self::connect_database(cache_settings).await.map_err(FileCacheError::DbError)?;
+
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT uuid, data_key FROM cache WHERE data_key IS NOT NULL").fetch_all(&pool).await.map_err(FileCacheError::DbError)?;
+
+        // All or nothing, a half rotated database would need both keys forever
+        let mut tx = pool.begin().await.map_err(FileCacheError::DbError)?;
+        for (uuid, wrapped) in &rows {
+            let data_key = keyring.unwrap(wrapped).map_err(FileCacheError::Encryption)?;
+            sqlx::query("UPDATE cache SET data_key = ? WHERE uuid = ?")
+                .bind(keyring.current.wrap(&data_key))
+                .bind(uuid)
+                .execute(&mut *tx)
+                .await
+                .map_err(FileCacheError::DbError)?;
+        }
+        tx.commit().await.map_err(FileCacheError::DbError)?;
+        Ok(rows.len())
+    }
+
+    pub async fn new(cache_settings: CacheSettings, library_path: &str) -> Result<Self, sqlx::Error> {
+        let library: PathBuf = library_path.into();
+        let owned_library = Self::prepare_library(&library).await?;
+        let pool = Self::connect_database(&cache_settings).await?;
+        Self::log_persistence(&pool, &cache_settings).await;
+
+        Self::init_schema(&pool).await?;
         debug!("sqlite table initialized");
 
-        // Initial feed
-        let rows: Vec<CacheEntryRow> = sqlx::query_as(
-            r#"
-            SELECT uuid, filename, expiration_utc, burn_after_read, read_count, file_size
-            FROM cache
-            "#,
-        )
-        .fetch_all(&pool)
-        .await?;
+        // A lost database would otherwise turn every blob into an orphan
+        let mut index_lost = false;
+        if owned_library {
+            let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM cache").fetch_one(&pool).await?;
+            let blobs = Self::count_blobs(&library).await?;
+            if rows == 0 && blobs > 0 {
+                if cache_settings.rebuild_empty_index {
+                    info!("The database is empty but the library holds {} files, rebuilding it from their sidecars", blobs);
+                    match Self::restore_sidecars(&library, &pool).await {
+                        Ok(summary) => info!("Rebuilt the database: {}", summary),
+                        Err(e) => {
+                            error!("Error rebuilding the database: {:?}", e);
+                            index_lost = true;
+                        }
+                    }
+                } else {
+                    warn!(
+                        "The database is empty but the library holds {} files, leaving them alone. Run `box-korvatunturi-org rebuild-index` to restore them from their sidecars or enable cache.storage.rebuild_empty_index",
+                        blobs
+                    );
+                    index_lost = true;
+                }
+            }
+        }
+
+        // Initial feed, the trash and quarantine stay in the database
+        let rows: Vec<CacheEntryRow> = sqlx::query_as(&format!("SELECT {} FROM cache WHERE deleted_utc IS NULL AND quarantined_utc IS NULL", ENTRY_COLUMNS)).fetch_all(&pool).await?;
 
         let mut cache = HashMap::new();
         for row in rows {
             let (uuid, entry) = row.into();
             if !entry.is_expired() {
                 cache.insert(uuid, entry);
+            } else if let Err(e) = Self::discard(&uuid, &library, &pool, cache_settings.trash_grace).await {
+                // Expired while we were down
+                warn!("Error dropping file: {:#?}", e)
             }
         }
         debug!("Cache entries populated");
+        let daily_usage = Mutex::new(DailyUsage::from_entries(&cache));
 
         // Cache cleanup in case we have orphaned data
-        info!("Cleaning up orphaned files");
-        let mut paths = read_dir(&library_path).await?;
-        while let Some(file) = paths.next_entry().await? {
-            if let Some(filename) = file.file_name().to_str() {
-                if !cache.contains_key(filename) {
-                    if let Err(e) = Self::delete_file(&library_path.into(), filename).await {
-                        error!("Error deleting file: {}", e)
-                    }
-                }
+        if owned_library && !index_lost {
+            info!("Cleaning up orphaned files");
+            let trashed: HashSet<String> = sqlx::query_as::<_, (String,)>("SELECT uuid FROM cache WHERE deleted_utc IS NOT NULL OR quarantined_utc IS NOT NULL")
+                .fetch_all(&pool)
+                .await?
+                .into_iter()
+                .map(|(uuid,)| uuid)
+                .collect();
+            let orphans = Self::remove_orphans(&library, |uuid| cache.contains_key(uuid) || trashed.contains(uuid), cache_settings.cleanup_dry_run).await?;
+            if orphans > 0 {
+                info!("{} {} orphaned files", if cache_settings.cleanup_dry_run { "Found" } else { "Removed" }, orphans);
             }
         }
 
         // Internal queues for sending and receiving events
-        let (alert_sender, mut alert_receiver) = mpsc::channel::<(String, SignalAction)>(3000);
+        let (alert_sender, alert_receiver) = mpsc::channel::<Signal>(QUEUE_CAPACITY);
+        // Shared so a restarted event task picks up where the failed one left off
+        let alert_receiver = Arc::new(Mutex::new(alert_receiver));
         let shared_cache = Arc::new(RwLock::new(cache));
-        let shared_mem = Arc::new(RwLock::new(CacheMemory::new(cache_settings.max_cache_memory.clone())));
-
-        // Background routines
-        tokio::spawn({
-            let cache = shared_cache.clone();
-            let cache_mem = shared_mem.clone();
-            let library = library_path.to_string().into();
-
-            let mut file_interval: Interval = interval(cache_settings.file_cleanup_interval);
-            let mut cache_interval: Interval = interval(cache_settings.cache_cleanup_interval);
-            async move {
-                info!("Starting background routines");
-                loop {
-                    select! {
-                        biased;
-                            // Channels
-                            maybe = alert_receiver.recv() => {
-                                if let Some((uuid, action)) = maybe {
-                                    match action {
-                                        // Delete file from database and disk (expired)
-                                        SignalAction::Delete => {
-                                            let remove = {
-                                                let mut rw_lock = cache.write().await;
-                                                if let Some(mut entry) = rw_lock.remove(&uuid) {
-                                                    flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
-                                                    true
-                                                } else {
-                                                    false
-                                                }
-                                            };
-
-                                            if remove {
-                                                if let Err(e) = Self::drop_item(&uuid, &library, &pool).await {
-                                                    warn!("Error dropping file: {:#?}", e)
-                                                }
-                                            }
-                                        }
-                                        SignalAction::NewFile => {
-                                            let lock = cache.read().await;
-                                            if let Some(entry) = lock.get(&uuid) {
-                                                if !entry.is_expired() {
-                                                    if let Err(e) = Self::push_to_db(&pool, &uuid, &entry).await {
-                                                        error!("Failed to save {} to DB: {e}", uuid);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        SignalAction::Accessed => {
-                                            let mut rw_lock = cache.write().await;
-                                            let mut burn_after_read = false;
-
-                                            if let Some(entry) = rw_lock.get_mut(&uuid) {
-                                                entry.read_count += 1;
-                                                burn_after_read = entry.burn_after_read;
-                                            }
-
-                                            if burn_after_read {
-                                                if let Some(mut entry) = rw_lock.remove(&uuid) {
-                                                    flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
-                                                    if let Err(e) = Self::drop_item(&uuid, &library, &pool).await {
-                                                        warn!("Error dropping file: {:#?}", e)
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            // File cleanup
-                            _ = file_interval.tick() => {
-                                trace!("Starting file cache maintenance routine.");
-                                let mut expired_entries = Vec::new();
-                                // Fetch expired entries
-                                {
-                                    let lock = cache.read().await;
-                                    for (uuid, entry) in lock.iter() {
-                                        if entry.is_expired() {
-                                            expired_entries.push(uuid.to_string());
-                                            continue;
-                                        }
-                                    }
-                                }
-                                // Remove expired entries
-                                {
-                                    let mut rw_lock = cache.write().await;
-                                    for uuid in &expired_entries {
-                                        debug!("Removing {} from cache", &uuid);
-
-                                        // Flushes usage from cache if present
-                                        if let Some(mut entry) = rw_lock.remove(uuid) {
-                                            flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
-                                        }
-                                    }
-                                }
-                                for uuid in &expired_entries {
-                                    if let Err(e) = Self::drop_item(&uuid, &library, &pool).await {
-                                        warn!("Error dropping file: {:#?}", e)
-                                    }
-                                }
-                        }
-                            // In memory cache cleanup
-                            _ = cache_interval.tick() => {
-                                trace!("Starting cache maintenance routine.");
-
-                                let mut rw_lock = cache.write().await;
-                                for (uuid, entry) in rw_lock.iter_mut() {
-                                    flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
-                                }
-                            }
+        let shared_mem = Arc::new(RwLock::new(CacheMemory::new(cache_settings.max_cache_memory)));
+        let shared_disk = Arc::new(DiskStatus::default());
+        let shared_consistency = Arc::new(Mutex::new(None));
+        let shared_scrub = Arc::new(ScrubStatus::default());
+        let shared_queue = Arc::new(QueueStats::default());
+        let supervisor = Arc::new(TaskRegistry::default());
+        let counters = Arc::new(CacheCounters::default());
+
+        // Background routines, each restarted on its own when it fails
+        info!("Starting background routines");
+        let shutdown = CancellationToken::new();
+        let background = Background {
+            cache: shared_cache.clone(),
+            cache_mem: shared_mem.clone(),
+            library: library.clone(),
+            pool: pool.clone(),
+            settings: Arc::new(cache_settings.clone()),
+            counters: counters.clone(),
+            shutdown: shutdown.clone(),
+        };
+        let mut tasks = Vec::new();
+        tasks.push(Self::supervise(&supervisor, "events", &background, {
+            let queue = shared_queue.clone();
+            move |background, health| Self::process_signals(background, alert_receiver.clone(), queue.clone(), health)
+        }));
+        tasks.push(Self::supervise_periodic(&supervisor, "expiry", &background, cache_settings.file_cleanup_interval, false, Self::expire_entries));
+        tasks.push(Self::supervise_periodic(&supervisor, "memory", &background, cache_settings.cache_cleanup_interval, false, Self::flush_memory));
+        // Free space monitor
+        tasks.push(Self::supervise_periodic(&supervisor, "disk", &background, cache_settings.disk_monitor_interval, false, {
+            let disk = shared_disk.clone();
+            move |background| {
+                let disk = disk.clone();
+                async move {
+                    trace!("Refreshing disk usage.");
+                    disk.refresh(&background.library, &background.settings).await;
+                }
+            }
+        }));
+        if let Some(grace) = cache_settings.trash_grace {
+            tasks.push(Self::supervise_periodic(&supervisor, "trash", &background, cache_settings.trash_sweep_interval, false, move |background| async move {
+                trace!("Sweeping the trash.");
+                match Self::sweep_trash(&background.library, &background.pool, grace).await {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Purged {} files from the trash", purged),
+                    Err(e) => error!("Error sweeping the trash: {:?}", e),
+                }
+            }));
+        }
+        // Database against library, the first run is at startup
+        tasks.push(Self::supervise_periodic(&supervisor, "consistency", &background, cache_settings.consistency_interval, false, {
+            let consistency = shared_consistency.clone();
+            move |background| {
+                let consistency = consistency.clone();
+                async move {
+                    trace!("Checking consistency.");
+                    match Self::check_consistency(
+                        &background.cache,
+                        &background.cache_mem,
+                        &background.library,
+                        &background.pool,
+                        background.settings.repair_policy,
+                        owned_library,
+                        background.settings.cleanup_dry_run,
+                    )
+                    .await
+                    {
+                        Ok(report) => *consistency.lock().await = Some(report),
+                        Err(e) => error!("Error checking consistency: {:?}", e),
                     }
                 }
             }
-        });
+        }));
+        // Database upkeep, pointless for one in memory. Not at startup, VACUUM rewrites the whole database
+        if cache_settings.is_persistent() {
+            tasks.push(Self::supervise_periodic(&supervisor, "maintenance", &background, cache_settings.db_maintenance_interval, true, |background| async move {
+                trace!("Maintaining the database.");
+                if let Err(e) = Self::maintain_database(&background.pool).await {
+                    error!("Error maintaining the database: {:?}", e);
+                }
+            }));
+        }
+        tasks.extend(Self::spawn_scrubber(&supervisor, &background, shared_scrub.clone()));
 
         Ok(Self {
             cache: shared_cache,
             sync: alert_sender,
             library: library_path.into(),
-            max_size: cache_settings.max_item_size.clone(),
-            cache_settings: cache_settings,
+            owned_library,
+            max_size: cache_settings.max_item_size,
+            cache_settings,
+            pool,
+            daily_usage,
+            disk: shared_disk,
+            consistency: shared_consistency,
+            scrub: shared_scrub,
+            queue: shared_queue,
+            library_probe: LibraryProbe::default(),
+            supervisor,
+            counters,
+            shutdown,
+            tasks: Mutex::new(tasks),
             cache_mem: shared_mem,
         })
     }
+
+    /// Applies queued signals in batches. On shutdown whatever is already queued still gets written
+    async fn process_signals(background: Background, receiver: Arc<Mutex<mpsc::Receiver<Signal>>>, queue: Arc<QueueStats>, health: Arc<TaskHealth>) {
+        let (settings, shutdown) = (&background.settings, &background.shutdown);
+        let mut receiver = receiver.lock().await;
+        loop {
+            select! {
+                biased;
+                // Handlers can't queue anything new
+                _ = shutdown.cancelled() => {
+                    receiver.close();
+                    let mut pending = Vec::new();
+                    while let Some(signal) = receiver.recv().await {
+                        pending.push(signal);
+                    }
+                    info!("Writing {} pending changes before shutting down", pending.len());
+                    let size = pending.len();
+                    let started = Instant::now();
+                    let committed = Self::apply_signals(pending, &background).await;
+                    queue.record(size, size, started.elapsed(), committed);
+                    if settings.is_persistent() && let Err(e) = Self::checkpoint(&background.pool).await {
+                        error!("Error checkpointing the database: {:?}", e);
+                    }
+                    break;
+                }
+                maybe = receiver.recv() => {
+                    let Some(first) = maybe else {
+                        break;
+                    };
+                    // Counts the one just received
+                    let depth = receiver.len() + 1;
+                    let batch = collect_batch(&mut receiver, first, settings.batch_size, settings.batch_latency, shutdown).await;
+                    let (size, started) = (batch.len(), Instant::now());
+                    let committed = Self::apply_signals(batch, &background).await;
+                    debug!("Applied {} signals in {:?}", size, started.elapsed());
+                    queue.record(depth, size, started.elapsed(), committed);
+                    health.beat();
+                }
+            }
+        }
+    }
+
+    /// Drops expired entries from the cache and the library
+    async fn expire_entries(background: Background) {
+        trace!("Starting file cache maintenance routine.");
+        let Background {
+            cache,
+            cache_mem,
+            library,
+            pool,
+            settings,
+            counters,
+            ..
+        } = background;
+        // Fetch expired entries
+        let expired_entries: Vec<String> = cache.read().await.iter().filter(|(_, entry)| entry.is_expired()).map(|(uuid, _)| uuid.to_string()).collect();
+        // Remove expired entries
+        {
+            let mut rw_lock = cache.write().await;
+            for uuid in &expired_entries {
+                debug!("Removing {} from cache", &uuid);
+
+                // Flushes usage from cache if present
+                if let Some(mut entry) = rw_lock.remove(uuid) {
+                    flush_entry!(entry, &uuid, settings.in_memory_ttl, cache_mem);
+                }
+            }
+        }
+        counters.record_expired(expired_entries.len());
+        for uuid in &expired_entries {
+            if let Err(e) = Self::discard(uuid, &library, &pool, settings.trash_grace).await {
+                warn!("Error dropping file: {:#?}", e)
+            }
+        }
+    }
+
+    /// Frees the memory of entries that haven't been read in a while
+    async fn flush_memory(background: Background) {
+        trace!("Starting cache maintenance routine.");
+        let Background { cache, cache_mem, settings, .. } = background;
+        let mut rw_lock = cache.write().await;
+        for (uuid, entry) in rw_lock.iter_mut() {
+            flush_entry!(entry, &uuid, settings.in_memory_ttl, cache_mem);
+        }
+    }
+
+    // The same database also holds the state of other modules
+    pub fn pool(&self) -> sqlx::Pool<sqlx::Sqlite> {
+        self.pool.clone()
+    }
+
+    /// Stops the background routines once everything they have queued is written, then closes the database
+    pub async fn shutdown(&self) {
+        info!("Shutting down the cache");
+        self.shutdown.cancel();
+        let tasks = std::mem::take(&mut *self.tasks.lock().await);
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("Background routine failed: {}", e);
+            }
+        }
+        self.pool.close().await;
+        info!("Cache shut down");
+    }
 }
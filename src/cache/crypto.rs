@@ -0,0 +1,271 @@
+use crate::settings::EncryptionConfig;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use std::{env, fmt, fs};
+
+// Blobs are sealed in independent chunks so ranges can be decrypted without reading the whole file
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const TAG_SIZE: usize = 16;
+pub const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    #[allow(dead_code)]
+    InvalidKey(String),
+    #[allow(dead_code)]
+    KeyUnavailable(String),
+    Decryption,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            CryptoError::KeyUnavailable(reason) => write!(f, "key unavailable: {}", reason),
+            CryptoError::Decryption => write!(f, "decryption failed"),
+        }
+    }
+}
+
+fn parse_key(raw: &str) -> Result<Key, CryptoError> {
+    let bytes = hex::decode(raw.trim()).map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+    if bytes.len() != 32 {
+        return Err(CryptoError::InvalidKey(format!("expected 32 bytes, got {}", bytes.len())));
+    }
+    Ok(*Key::from_slice(&bytes))
+}
+
+// Server wide key which only ever encrypts the per-file data keys
+#[derive(Clone)]
+pub struct MasterKey(Key);
+
+impl MasterKey {
+    /// Reads a hex encoded key from a file, falling back to an environment variable
+    pub fn load(file: Option<&str>, env_var: Option<&str>) -> Result<Option<Self>, CryptoError> {
+        if let Some(path) = file {
+            let raw = fs::read_to_string(path).map_err(|e| CryptoError::KeyUnavailable(format!("{}: {}", path, e)))?;
+            return parse_key(&raw).map(|k| Some(Self(k)));
+        }
+        if let Some(var) = env_var
+            && let Ok(raw) = env::var(var)
+        {
+            return parse_key(&raw).map(|k| Some(Self(k)));
+        }
+        Ok(None)
+    }
+
+    pub fn wrap(&self, data_key: &DataKey) -> String {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // Encrypting 32 bytes into a Vec can't fail
+        let sealed = cipher.encrypt(&nonce, data_key.0.as_slice()).expect("wrapping a data key");
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(sealed);
+        hex::encode(wrapped)
+    }
+
+    pub fn unwrap(&self, wrapped: &str) -> Result<DataKey, CryptoError> {
+        let raw = hex::decode(wrapped).map_err(|_| CryptoError::Decryption)?;
+        if raw.len() <= NONCE_SIZE {
+            return Err(CryptoError::Decryption);
+        }
+        let (nonce, sealed) = raw.split_at(NONCE_SIZE);
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let key = cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| CryptoError::Decryption)?;
+        if key.len() != 32 {
+            return Err(CryptoError::Decryption);
+        }
+        Ok(DataKey(*Key::from_slice(&key)))
+    }
+}
+
+// The current master key plus the one being rotated out, if any
+#[derive(Clone)]
+pub struct KeyRing {
+    pub current: MasterKey,
+    pub previous: Option<MasterKey>,
+}
+
+impl KeyRing {
+    pub fn load(conf: &EncryptionConfig) -> Result<Option<Self>, CryptoError> {
+        let Some(current) = MasterKey::load(conf.master_key_file.as_deref(), Some(&conf.master_key_env))? else {
+            if conf.enabled {
+                return Err(CryptoError::KeyUnavailable("encryption is enabled but no master key is configured".into()));
+            }
+            return Ok(None);
+        };
+        let previous = MasterKey::load(conf.previous_master_key_file.as_deref(), Some(&conf.previous_master_key_env))?;
+        Ok(Some(Self { current, previous }))
+    }
+
+    pub fn unwrap(&self, wrapped: &str) -> Result<DataKey, CryptoError> {
+        match self.current.unwrap(wrapped) {
+            Ok(key) => Ok(key),
+            Err(e) => match &self.previous {
+                Some(previous) => previous.unwrap(wrapped),
+                None => Err(e),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DataKey(Key);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        Self(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    // The data key is unique per file so the chunk index is enough of a nonce,
+    // the final chunk is flagged so truncating the blob can't go unnoticed
+    fn nonce(index: u64, last: bool) -> Nonce {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&index.to_le_bytes());
+        nonce[NONCE_SIZE - 1] = last as u8;
+        *Nonce::from_slice(&nonce)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let chunk_count = plaintext.len().div_ceil(CHUNK_SIZE).max(1);
+        let mut sealed = Vec::with_capacity(plaintext.len() + chunk_count * TAG_SIZE);
+
+        for index in 0..chunk_count {
+            let start = index * CHUNK_SIZE;
+            let end = (start + CHUNK_SIZE).min(plaintext.len());
+            let nonce = Self::nonce(index as u64, index + 1 == chunk_count);
+            sealed.extend(cipher.encrypt(&nonce, &plaintext[start..end]).expect("encrypting a chunk"));
+        }
+        sealed
+    }
+
+    pub fn decrypt_chunk(&self, index: u64, last: bool, chunk: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        cipher.decrypt(&Self::nonce(index, last), chunk).map_err(|_| CryptoError::Decryption)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        // Even an empty file has a tag, anything shorter was truncated
+        if sealed.len() < TAG_SIZE {
+            return Err(CryptoError::Decryption);
+        }
+        let chunk_count = chunk_count(sealed.len() as u64) as usize;
+        let mut plaintext = Vec::with_capacity(plaintext_len(sealed.len() as u64) as usize);
+        let mut saw_last = false;
+        for (index, chunk) in sealed.chunks(SEALED_CHUNK_SIZE).enumerate() {
+            let last = index + 1 == chunk_count;
+            plaintext.extend(self.decrypt_chunk(index as u64, last, chunk)?);
+            saw_last |= last;
+        }
+        if !saw_last {
+            return Err(CryptoError::Decryption);
+        }
+        Ok(plaintext)
+    }
+}
+
+pub fn chunk_count(sealed_len: u64) -> u64 {
+    sealed_len.div_ceil(SEALED_CHUNK_SIZE as u64).max(1)
+}
+
+pub fn plaintext_len(sealed_len: u64) -> u64 {
+    sealed_len.saturating_sub(chunk_count(sealed_len) * TAG_SIZE as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_across_chunk_boundaries() {
+        let key = DataKey::generate();
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 5] {
+            let plaintext = sample(len);
+            let sealed = key.encrypt(&plaintext);
+            assert_eq!(plaintext_len(sealed.len() as u64), len as u64);
+            assert_eq!(key.decrypt(&sealed).unwrap(), plaintext, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_blobs() {
+        let key = DataKey::generate();
+        let sealed = key.encrypt(&sample(2 * CHUNK_SIZE + 10));
+
+        // Cut at a chunk boundary, every remaining chunk still authenticates on its own
+        assert!(key.decrypt(&sealed[..2 * SEALED_CHUNK_SIZE]).is_err());
+        assert!(key.decrypt(&sealed[..SEALED_CHUNK_SIZE]).is_err());
+        // Cut in the middle of a chunk
+        assert!(key.decrypt(&sealed[..sealed.len() - 1]).is_err());
+        // Down to nothing, or less than a tag
+        assert!(key.decrypt(&[]).is_err());
+        assert!(key.decrypt(&sealed[..TAG_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_an_empty_file_without_its_tag() {
+        let key = DataKey::generate();
+        let sealed = key.encrypt(&[]);
+        assert_eq!(sealed.len(), TAG_SIZE);
+        assert!(key.decrypt(&sealed).unwrap().is_empty());
+        assert!(key.decrypt(&sealed[..0]).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let key = DataKey::generate();
+        let mut sealed = key.encrypt(&sample(3 * CHUNK_SIZE));
+        let (first, rest) = sealed.split_at_mut(SEALED_CHUNK_SIZE);
+        first.swap_with_slice(&mut rest[..SEALED_CHUNK_SIZE]);
+        assert!(key.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering_and_the_wrong_key() {
+        let key = DataKey::generate();
+        let mut sealed = key.encrypt(&sample(100));
+        assert!(DataKey::generate().decrypt(&sealed).is_err());
+        sealed[10] ^= 1;
+        assert!(key.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn chunks_only_decrypt_at_their_own_position() {
+        let key = DataKey::generate();
+        let sealed = key.encrypt(&sample(2 * CHUNK_SIZE));
+        let second = &sealed[SEALED_CHUNK_SIZE..];
+        assert_eq!(key.decrypt_chunk(1, true, second).unwrap(), sample(2 * CHUNK_SIZE)[CHUNK_SIZE..]);
+        assert!(key.decrypt_chunk(1, false, second).is_err());
+        assert!(key.decrypt_chunk(0, true, second).is_err());
+    }
+
+    #[test]
+    fn key_ring_falls_back_to_the_previous_key() {
+        let old = MasterKey(parse_key(&"11".repeat(32)).unwrap());
+        let new = MasterKey(parse_key(&"22".repeat(32)).unwrap());
+        let data_key = DataKey::generate();
+        let wrapped = old.wrap(&data_key);
+
+        assert!(new.unwrap(&wrapped).is_err());
+        let ring = KeyRing { current: new, previous: Some(old) };
+        let unwrapped = ring.unwrap(&wrapped).unwrap();
+        assert_eq!(unwrapped.decrypt(&data_key.encrypt(b"hello")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn parses_only_32_byte_hex_keys() {
+        assert!(parse_key(&"ab".repeat(32)).is_ok());
+        assert!(parse_key(&format!(" {}\n", "ab".repeat(32))).is_ok());
+        assert!(parse_key(&"ab".repeat(16)).is_err());
+        assert!(parse_key("not hex").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use super::{
+    core::{FileCache, FileCacheError},
+    settings::CacheSettings,
+};
+use log::{debug, info, warn};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::time::Instant;
+
+// Default name of the database, kept in the library next to the blobs
+pub const DATABASE_FILE: &str = "index.sqlite";
+// WAL lets readers carry on while a write is in progress
+const MAX_CONNECTIONS: u32 = 4;
+
+impl CacheSettings {
+    /// Whether uploads survive a restart
+    pub fn is_persistent(&self) -> bool {
+        // Also catches urls like `sqlite::memory:`
+        !(self.database_path.contains(":memory:") || self.database_path.contains("mode=memory"))
+    }
+}
+
+/// Database connection and upkeep
+impl FileCache {
+    /// Opens the database, creating it if needed. Accepts a plain path or a `sqlite:` url
+    pub async fn connect_database(cache_settings: &CacheSettings) -> Result<sqlx::Pool<sqlx::Sqlite>, sqlx::Error> {
+        let path = &cache_settings.database_path;
+        // Every connection to an in memory database gets a database of its own
+        if !cache_settings.is_persistent() {
+            return SqlitePoolOptions::new().max_connections(1).connect(path).await;
+        }
+
+        let options = match path.starts_with("sqlite:") {
+            true => SqliteConnectOptions::from_str(path)?,
+            false => {
+                if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                SqliteConnectOptions::new().filename(path)
+            }
+        };
+        let options = options
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            // Durable with WAL as long as the OS doesn't crash, and much cheaper than FULL
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(cache_settings.db_busy_timeout);
+        SqlitePoolOptions::new().max_connections(MAX_CONNECTIONS).connect_with(options).await
+    }
+
+    /// States where uploads are kept, a database in memory forgets everything on restart
+    pub(super) async fn log_persistence(pool: &sqlx::Pool<sqlx::Sqlite>, cache_settings: &CacheSettings) {
+        if !cache_settings.is_persistent() {
+            warn!("The database is in memory, uploads are forgotten when the server restarts. Set cache.database_path to keep them");
+            return;
+        }
+        let journal: String = match sqlx::query_scalar("PRAGMA journal_mode").fetch_one(pool).await {
+            Ok(journal) => journal,
+            Err(e) => format!("unknown ({})", e),
+        };
+        info!("Persisting uploads in {} ({} journal, {:?} busy timeout)", cache_settings.database_path, journal, cache_settings.db_busy_timeout);
+        if !journal.eq_ignore_ascii_case("wal") {
+            warn!("The database isn't in WAL mode, readers will wait on writers");
+        }
+    }
+
+    /// Moves everything in the WAL into the database file
+    pub(super) async fn checkpoint(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), FileCacheError> {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(pool).await.map_err(FileCacheError::DbError)?;
+        Ok(())
+    }
+
+    /// Checkpoints the WAL, refreshes the query planner statistics and reclaims free pages
+    pub(super) async fn maintain_database(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), FileCacheError> {
+        let started = Instant::now();
+        for statement in ["PRAGMA wal_checkpoint(TRUNCATE)", "ANALYZE", "VACUUM"] {
+            debug!("Running {}", statement);
+            sqlx::query(statement).execute(pool).await.map_err(FileCacheError::DbError)?;
+        }
+        info!("Database maintenance took {} ms", started.elapsed().as_millis());
+        Ok(())
+    }
+}
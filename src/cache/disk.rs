@@ -0,0 +1,182 @@
+use crate::flush_entry;
+
+use super::{
+    core::{FileCache, FileCacheError},
+    settings::CacheSettings,
+};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::{fs::read_dir, time::Duration};
+
+// Last seen state of the filesystem holding the library, refreshed by the monitor
+#[derive(Default)]
+pub struct DiskStatus {
+    free_bytes: AtomicU64,
+    library_bytes: AtomicU64,
+    low: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct DiskUsage {
+    pub free_bytes: u64,
+    pub library_bytes: u64,
+    pub max_library_size: Option<u64>,
+    pub min_free_space: Option<u64>,
+}
+
+fn available_space(library: &Path) -> Option<u64> {
+    match fs2::available_space(library) {
+        Ok(free) => Some(free),
+        Err(e) => {
+            warn!("Unable to read free space of {}: {}", library.display(), e);
+            None
+        }
+    }
+}
+
+// Bytes needed on top of what's there for `size` more to fit the budget
+fn shortfall(settings: &CacheSettings, library_bytes: u64, free_bytes: Option<u64>, size: u64) -> u64 {
+    let over_budget = settings.max_library_size.map(|max| (library_bytes + size).saturating_sub(max)).unwrap_or(0);
+    let under_reserve = match (settings.min_free_space, free_bytes) {
+        (Some(min), Some(free)) => (min + size).saturating_sub(free),
+        _ => 0,
+    };
+    over_budget.max(under_reserve)
+}
+
+impl DiskStatus {
+    // Walks the library, run from the background routines
+    pub(super) async fn refresh(&self, library: &Path, settings: &CacheSettings) {
+        let mut library_bytes = 0;
+        match read_dir(library).await {
+            Ok(mut paths) => {
+                while let Ok(Some(file)) = paths.next_entry().await {
+                    if let Ok(metadata) = file.metadata().await
+                        && metadata.is_file()
+                    {
+                        library_bytes += metadata.len();
+                    }
+                }
+            }
+            Err(e) => warn!("Unable to read library {}: {}", library.display(), e),
+        }
+        let free_bytes = available_space(library);
+
+        self.library_bytes.store(library_bytes, Ordering::Relaxed);
+        if let Some(free) = free_bytes {
+            self.free_bytes.store(free, Ordering::Relaxed);
+        }
+
+        // Only log when crossing the threshold so a full box doesn't flood the log
+        let low = shortfall(settings, library_bytes, free_bytes, 0) > 0;
+        if low != self.low.swap(low, Ordering::Relaxed) {
+            match low {
+                true => warn!("Storage is full: {} bytes in the library, {:?} bytes free", library_bytes, free_bytes),
+                false => info!("Storage has room again"),
+            }
+        }
+    }
+
+    // Free space is read fresh, the library size is the one from the last refresh
+    pub(super) fn check_room(&self, library: &Path, settings: &CacheSettings) -> Result<(), String> {
+        let free_bytes = available_space(library);
+        if free_bytes == Some(0) {
+            return Err("the disk is full".to_string());
+        }
+        match shortfall(settings, self.library_bytes.load(Ordering::Relaxed), free_bytes, 0) {
+            0 => Ok(()),
+            missing => Err(format!("{} bytes over the storage budget", missing)),
+        }
+    }
+}
+
+/// Disk budget
+impl FileCache {
+    pub fn disk_usage(&self) -> DiskUsage {
+        DiskUsage {
+            free_bytes: self.disk.free_bytes.load(Ordering::Relaxed),
+            library_bytes: self.disk.library_bytes.load(Ordering::Relaxed),
+            max_library_size: self.cache_settings.max_library_size,
+            min_free_space: self.cache_settings.min_free_space,
+        }
+    }
+
+    // Bytes missing for `size` more to fit, along with what live entries and the trash hold
+    async fn space_needed(&self, size: u64) -> (u64, u64, u64) {
+        if self.cache_settings.max_library_size.is_none() && self.cache_settings.min_free_space.is_none() {
+            return (0, 0, 0);
+        }
+        let live_bytes = {
+            let lock = self.cache.read().await;
+            lock.values().map(|entry| entry.size).sum::<u64>()
+        };
+        // Trashed blobs are still on disk
+        let trash_bytes = self.trash_bytes().await;
+        (shortfall(&self.cache_settings, live_bytes + trash_bytes, available_space(&self.library), size), live_bytes, trash_bytes)
+    }
+
+    /// Whether `size` more bytes could fit, counting what purging the trash and eviction would free. Never deletes anything
+    pub async fn has_room_for(&self, size: u64) -> bool {
+        let (needed, live_bytes, trash_bytes) = self.space_needed(size).await;
+        let evictable = if self.cache_settings.evict_to_make_room { live_bytes } else { 0 };
+        needed <= trash_bytes + evictable
+    }
+
+    /// Makes sure `size` more bytes fit within the disk budget, purging the trash and evicting files if configured to
+    pub async fn ensure_space(&self, size: u64) -> Result<(), FileCacheError> {
+        let (needed, _, _) = self.space_needed(size).await;
+        if needed == 0 {
+            return Ok(());
+        }
+        // The trash goes first and regardless of `evict_to_make_room`, it isn't live data
+        let freed = self.purge_for_space(needed).await;
+        if freed >= needed {
+            return Ok(());
+        }
+        if !self.cache_settings.evict_to_make_room {
+            debug!("Refusing {} bytes, {} short", size, needed - freed);
+            return Err(FileCacheError::NoSpaceLeftOnDevice);
+        }
+        self.evict(needed - freed).await
+    }
+
+    // Drops the files closest to expiring until `needed` bytes are freed, they skip the trash as that wouldn't free anything
+    async fn evict(&self, needed: u64) -> Result<(), FileCacheError> {
+        let evicted = {
+            let mut lock = self.cache.write().await;
+            let mut candidates: Vec<_> = lock.iter().filter(|(_, entry)| !entry.is_expired()).map(|(uuid, entry)| (uuid.clone(), entry.expiration, entry.size)).collect();
+            candidates.sort_by_key(|(_, expiration, _)| *expiration);
+
+            let mut freed = 0;
+            let mut evicted = Vec::new();
+            for (uuid, _, size) in candidates {
+                if freed >= needed {
+                    break;
+                }
+                freed += size;
+                evicted.push(uuid);
+            }
+            // Evicting everything and still not fitting helps nobody
+            if freed < needed {
+                return Err(FileCacheError::NoSpaceLeftOnDevice);
+            }
+
+            for uuid in &evicted {
+                if let Some(mut entry) = lock.remove(uuid) {
+                    flush_entry!(entry, uuid, Duration::ZERO, self.cache_mem);
+                }
+            }
+            evicted
+        };
+
+        for uuid in &evicted {
+            info!("Evicting {} to make room", uuid);
+            if let Err(e) = Self::drop_item(uuid, &self.library, &self.pool).await {
+                warn!("Error dropping file: {:#?}", e)
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,53 @@
+use super::{datetime_to_instant, expiry::DurationValue};
+use argon2::password_hash::rand_core::RngCore;
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use sqlx::FromRow;
 use tokio::time::Duration;
 use tokio::time::Instant;
 
 #[derive(Deserialize, Clone)]
 pub struct FileOptions {
-    pub expires_in: Option<u64>,
+    // Seconds or a duration such as "7d" or "1w2d"
+    pub expires_in: Option<DurationValue>,
+    // RFC 3339 timestamp or "never"
+    pub expires_at: Option<String>,
     pub filename: Option<String>,
     pub burn_after_read: Option<bool>,
+    pub private: Option<bool>,
+    pub password: Option<String>,
+    // End-to-end encrypted upload, the server only ever sees ciphertext
+    pub e2e: Option<bool>,
+    // Client encrypted filename and content type, required for e2e uploads
+    pub metadata: Option<String>,
+    // The file expires after this many downloads
+    pub max_downloads: Option<u64>,
+    // Every download pushes the expiry back, up to the server's hard cap
+    pub sliding: Option<bool>,
+    // RFC 3339 timestamp, the file can't be downloaded before it
+    pub available_from: Option<String>,
+    // Start the expiry countdown at `available_from` instead of now
+    pub expire_after_publication: Option<bool>,
+}
+
+// Changes to an existing upload, anything left out stays as it is
+#[derive(Deserialize)]
+pub struct FileChanges {
+    pub expires_in: Option<DurationValue>,
+    pub expires_at: Option<String>,
+    pub burn_after_read: Option<bool>,
+    // 0 removes the limit
+    pub max_downloads: Option<u64>,
+    pub filename: Option<String>,
+    // A time in the past publishes the file right away
+    pub available_from: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -28,42 +65,165 @@ pub struct CacheEntry {
     pub(super) burn_after_read: bool,
     pub(super) read_count: i64,
     pub(super) len: i64,
+    pub(super) private: bool,
+    #[serde(skip_serializing)]
+    pub(super) password_hash: Option<String>,
+    // Data key sealed with the master key, only set for blobs encrypted at rest
+    #[serde(skip_serializing)]
+    pub(super) wrapped_key: Option<String>,
+    pub(super) e2e: bool,
+    #[serde(skip_serializing)]
+    pub(super) metadata: Option<String>,
+    // "user:<name>", "token:<name>" or "ip:<address>" of whoever uploaded the file
+    pub(super) owner: Option<String>,
+    // Exact size in bytes, `len` is rounded to kilobytes
+    pub(super) size: u64,
+    // Unknown for entries created by older versions
+    pub(super) created: Option<DateTime<Utc>>,
+    // SHA-256 of the token handed out on upload, lets the uploader manage the file
+    #[serde(skip_serializing)]
+    pub(super) deletion_token: Option<String>,
+    pub(super) max_downloads: Option<i64>,
+    // Set for sliding expiry, downloads can push the expiry up to here
+    #[serde(skip_serializing)]
+    pub(super) renew_until: Option<Instant>,
+    // Publication time, the file is stored but can't be downloaded before it
+    pub(super) available_from: Option<DateTime<Utc>>,
+    // SHA-256 of the blob as stored, unknown for entries created by older versions
+    #[serde(skip_serializing)]
+    pub(super) checksum: Option<String>,
 }
 
 impl CacheEntry {
-    pub(super) fn new(name: &str, data: Option<Bytes>, len: i64, burn_after_read: bool, ttl: Duration) -> Self {
+    pub(super) fn new(name: &str, data: Option<Bytes>, len: i64, burn_after_read: bool, private: bool, ttl: Duration) -> Self {
         // bytes to kb
         let len_kb = (len / 1000).max(1);
         Self {
             upload_name: name.to_string(),
             accessed: Instant::now(),
-            data: data,
+            data,
             len: len_kb,
-            burn_after_read: burn_after_read,
+            burn_after_read,
             expiration: Instant::now() + ttl,
             read_count: 0,
+            private,
+            password_hash: None,
+            wrapped_key: None,
+            e2e: false,
+            metadata: None,
+            owner: None,
+            size: len.max(0) as u64,
+            created: Some(Utc::now()),
+            deletion_token: None,
+            max_downloads: None,
+            renew_until: None,
+            available_from: None,
+            checksum: None,
+        }
+    }
+
+    // Returns the token for the uploader and the hash to store
+    pub(super) fn new_deletion_token() -> (String, String) {
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = hex::encode(token);
+        let hash = hex::encode(Sha256::digest(token.as_bytes()));
+        (token, hash)
+    }
+
+    pub fn verify_deletion_token(&self, token: &str) -> bool {
+        match &self.deletion_token {
+            Some(hash) => hex::encode(Sha256::digest(token.as_bytes())) == *hash,
+            None => false,
         }
     }
 
+    // Scheduled uploads stay hidden until their publication time
+    pub fn available_from(&self) -> Option<DateTime<Utc>> {
+        self.available_from.filter(|from| *from > Utc::now())
+    }
+
+    // Private entries can only be downloaded through a signed URL
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn is_e2e(&self) -> bool {
+        self.e2e
+    }
+
+    pub fn metadata(&self) -> Option<&str> {
+        self.metadata.as_deref()
+    }
+
+    // Keeps anything the client encrypted out of listings
+    pub(super) fn masked(&self) -> Self {
+        let mut entry = self.clone();
+        if entry.e2e {
+            entry.upload_name = "<end-to-end encrypted>".to_string();
+        }
+        entry
+    }
+
+    pub fn is_password_protected(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    // Argon2 is slow on purpose, call this from a blocking context
+    pub fn verify_password(&self, password: &str) -> bool {
+        let Some(hash) = &self.password_hash else {
+            return true;
+        };
+        match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub(super) fn hash_password(password: &str) -> Option<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(password.as_bytes(), &salt).ok().map(|hash| hash.to_string())
+    }
+
     pub(super) fn update(&mut self, data: Bytes) {
         self.accessed = Instant::now();
         self.data = Some(data)
     }
 
+    // Pushes the expiry to `window` from now, never past `renew_until` and never backwards
+    pub(super) fn slide(&mut self, window: Duration) -> bool {
+        let Some(renew_until) = self.renew_until else {
+            return false;
+        };
+        let target = (Instant::now() + window).min(renew_until);
+        if target <= self.expiration {
+            return false;
+        }
+        self.expiration = target;
+        true
+    }
+
     pub(super) fn is_expired(&self) -> bool {
-        self.expiration < Instant::now() || self.burn_after_read && self.read_count > 0
+        self.expiration < Instant::now() || self.is_used_up()
+    }
+
+    // Burned after reading or out of downloads, as opposed to running out of time
+    pub(super) fn is_used_up(&self) -> bool {
+        self.burn_after_read && self.read_count > 0 || self.max_downloads.is_some_and(|max| self.read_count >= max)
     }
 
     pub(super) fn flush(&mut self, cache_ttl: Duration) -> Option<i64> {
-        if Instant::now() - self.accessed >= cache_ttl {
-            if self.data.take().is_some() {
-                return Some(self.len);
-            }
+        if Instant::now() - self.accessed >= cache_ttl && self.data.take().is_some() {
+            return Some(self.len);
         }
         None
     }
 }
 
+// Everything `CacheEntryRow` is read from
+pub(super) const ENTRY_COLUMNS: &str =
+    "uuid, filename, expiration_utc, burn_after_read, read_count, file_size, private, password_hash, data_key, e2e, metadata, owner, size_bytes, created_utc, deletion_token, max_downloads, renew_until_utc, available_from_utc, blob_sha256";
+
 #[derive(FromRow)]
 pub struct CacheEntryRow {
     uuid: String,
@@ -72,21 +232,23 @@ pub struct CacheEntryRow {
     burn_after_read: i8,
     file_size: i64,
     read_count: i64,
+    private: i8,
+    password_hash: Option<String>,
+    data_key: Option<String>,
+    e2e: i8,
+    metadata: Option<String>,
+    owner: Option<String>,
+    size_bytes: Option<i64>,
+    created_utc: Option<DateTime<Utc>>,
+    deletion_token: Option<String>,
+    max_downloads: Option<i64>,
+    renew_until_utc: Option<DateTime<Utc>>,
+    available_from_utc: Option<DateTime<Utc>>,
+    blob_sha256: Option<String>,
 }
 
 impl From<CacheEntryRow> for (String, CacheEntry) {
     fn from(row: CacheEntryRow) -> Self {
-        let now_utc = Utc::now();
-        let now_instant = Instant::now();
-        let expiration = {
-            if row.expiration_utc > now_utc {
-                let diff = (row.expiration_utc - now_utc).to_std().unwrap_or_default();
-                now_instant + diff
-            } else {
-                now_instant
-            }
-        };
-
         let entry = CacheEntry {
             upload_name: row.filename,
             accessed: Instant::now(),
@@ -94,7 +256,20 @@ impl From<CacheEntryRow> for (String, CacheEntry) {
             len: row.file_size,
             burn_after_read: row.burn_after_read == 1,
             read_count: row.read_count,
-            expiration: expiration,
+            expiration: datetime_to_instant(&row.expiration_utc),
+            private: row.private == 1,
+            password_hash: row.password_hash,
+            wrapped_key: row.data_key,
+            e2e: row.e2e == 1,
+            metadata: row.metadata,
+            owner: row.owner,
+            size: row.size_bytes.unwrap_or(row.file_size * 1000).max(0) as u64,
+            created: row.created_utc,
+            deletion_token: row.deletion_token,
+            max_downloads: row.max_downloads,
+            renew_until: row.renew_until_utc.as_ref().map(datetime_to_instant),
+            available_from: row.available_from_utc,
+            checksum: row.blob_sha256,
         };
         (row.uuid, entry)
     }
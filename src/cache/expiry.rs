@@ -0,0 +1,230 @@
+use crate::settings::{RetentionConfig, TtlConfig, TtlPolicy};
+use crate::users::Role;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// "Never" is a hundred years out, Instant has no notion of forever
+const NEVER: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+// Plain seconds from JSON, or a string such as "3600", "90m" or "1w2d"
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+/// Parses "90m", "7d", "1w2d" and so on, a bare number is seconds
+pub fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let raw = raw.trim().to_lowercase();
+    if raw.is_empty() {
+        return Err("duration is empty".to_string());
+    }
+    if let Ok(secs) = raw.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in raw.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(format!("unknown unit '{}' in \"{}\", use s, m, h, d or w", c, raw)),
+        };
+        if number.is_empty() {
+            return Err(format!("missing number before '{}' in \"{}\"", c, raw));
+        }
+        let value: u64 = number.parse().map_err(|_| format!("\"{}\" is too long", raw))?;
+        total = value.checked_mul(unit).and_then(|secs| total.checked_add(secs)).ok_or_else(|| format!("\"{}\" is too long", raw))?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(format!("missing unit after {} in \"{}\"", number, raw));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+impl DurationValue {
+    pub fn to_duration(&self) -> Result<Duration, String> {
+        match self {
+            DurationValue::Seconds(secs) => Ok(Duration::from_secs(*secs)),
+            DurationValue::Text(raw) => parse_duration(raw),
+        }
+    }
+}
+
+/// RFC 3339 timestamp from a request, `field` names it in the error
+pub fn parse_timestamp(raw: &str, field: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(raw.trim()).map(|at| at.with_timezone(&Utc)).map_err(|e| format!("{} \"{}\" is not an RFC 3339 timestamp: {}", field, raw, e))
+}
+
+// A requested lifetime before the policy has had its say
+pub enum Expiry {
+    In(Duration),
+    Never,
+}
+
+impl Expiry {
+    /// Reads `expires_in` / `expires_at` (RFC 3339 or "never"), at most one of them may be set
+    pub fn from_options(expires_in: Option<&DurationValue>, expires_at: Option<&str>) -> Result<Option<Self>, String> {
+        match (expires_in, expires_at) {
+            (Some(_), Some(_)) => Err("use either expires_in or expires_at, not both".to_string()),
+            (Some(expires_in), None) => expires_in.to_duration().map(|d| Some(Expiry::In(d))),
+            (None, Some(at)) if at.trim().eq_ignore_ascii_case("never") => Ok(Some(Expiry::Never)),
+            (None, Some(at)) => {
+                let at = parse_timestamp(at, "expires_at")?;
+                match (at - Utc::now()).to_std() {
+                    Ok(diff) => Ok(Some(Expiry::In(diff))),
+                    Err(_) => Err("expires_at is in the past".to_string()),
+                }
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+// Lifetime limits per ACL group: "anonymous" for whitelisted addresses, "uploader" and "admin" for accounts and tokens
+#[derive(Clone, Default)]
+pub struct TtlPolicies {
+    default: TtlPolicy,
+    groups: HashMap<String, TtlPolicy>,
+}
+
+impl From<&TtlConfig> for TtlPolicies {
+    fn from(conf: &TtlConfig) -> Self {
+        Self {
+            default: conf.default.clone(),
+            groups: conf.groups.clone(),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+impl TtlPolicies {
+    fn policy(&self, role: Option<Role>) -> TtlPolicy {
+        let group = role.map(|r| r.to_string()).unwrap_or_else(|| "anonymous".to_string());
+        match self.groups.get(&group) {
+            Some(policy) => TtlPolicy {
+                min_ttl: policy.min_ttl.or(self.default.min_ttl),
+                max_ttl: policy.max_ttl.or(self.default.max_ttl),
+                allow_never: policy.allow_never.or(self.default.allow_never),
+            },
+            None => self.default.clone(),
+        }
+    }
+
+    /// Turns a requested lifetime into an expiration instant, `None` falls back to `default`
+    pub fn resolve(&self, role: Option<Role>, requested: Option<Expiry>, default: Duration) -> Result<Instant, String> {
+        let policy = self.policy(role);
+        let min = policy.min_ttl.map(Duration::from_secs);
+        let max = policy.max_ttl.map(Duration::from_secs);
+
+        let ttl = match requested {
+            Some(Expiry::Never) if policy.allow_never.unwrap_or(false) => NEVER,
+            Some(Expiry::Never) => return Err("files that never expire are not allowed".to_string()),
+            Some(Expiry::In(ttl)) => {
+                if let Some(min) = min
+                    && ttl < min
+                {
+                    return Err(format!("expiry must be at least {}", format_duration(min)));
+                }
+                if let Some(max) = max
+                    && ttl > max
+                {
+                    return Err(format!("expiry can be at most {}", format_duration(max)));
+                }
+                ttl
+            }
+            // The server default is bent to fit rather than refused, the client didn't ask for it
+            None => {
+                let ttl = max.map_or(default, |max| default.min(max));
+                min.map_or(ttl, |min| ttl.max(min))
+            }
+        };
+        Instant::now().checked_add(ttl.min(NEVER)).ok_or_else(|| "expiry is too far away".to_string())
+    }
+}
+
+// 0x0.st style curve, small files get close to `max_age` and files at `max_size` get `min_age`
+#[derive(Clone, Debug)]
+pub struct RetentionCurve {
+    min_age: Duration,
+    max_age: Duration,
+    max_size: u64,
+}
+
+impl RetentionCurve {
+    pub fn new(conf: &RetentionConfig, max_item_size: u64) -> Option<Self> {
+        let (min_age, max_age) = (conf.min_age?, conf.max_age?);
+        Some(Self {
+            min_age: Duration::from_secs(min_age.min(max_age)),
+            max_age: Duration::from_secs(max_age.max(min_age)),
+            max_size: conf.max_size.unwrap_or(max_item_size).max(1),
+        })
+    }
+
+    /// Longest a file of `size` bytes may live: min_age + (max_age - min_age) * (1 - size / max_size)^3
+    pub fn max_lifetime(&self, size: u64) -> Duration {
+        let ratio = (size as f64 / self.max_size as f64).min(1.0);
+        let span = (self.max_age - self.min_age).as_secs_f64();
+        self.min_age + Duration::from_secs_f64(span * (1.0 - ratio).powi(3))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bare_seconds_and_units() {
+        assert_eq!(parse_duration("3600"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration(" 1W2d "), Ok(Duration::from_secs(604800 + 2 * 86400)));
+        assert_eq!(parse_duration("1h30m15s"), Ok(Duration::from_secs(3600 + 30 * 60 + 15)));
+    }
+
+    #[test]
+    fn rejects_malformed_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("   ").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("-5m").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(parse_duration(&format!("{}w", u64::MAX)).is_err());
+        assert!(parse_duration(&format!("{}s1s", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn reads_seconds_and_text_values() {
+        assert_eq!(DurationValue::Seconds(60).to_duration(), Ok(Duration::from_secs(60)));
+        assert_eq!(DurationValue::Text("1m".to_string()).to_duration(), Ok(Duration::from_secs(60)));
+    }
+}
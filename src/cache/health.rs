@@ -0,0 +1,97 @@
+use super::core::FileCache;
+use serde::Serialize;
+use std::path::Path;
+use tokio::fs::{remove_file, write};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant, timeout};
+
+// Written and removed again to prove the library takes writes
+const PROBE_FILE: &str = ".ready";
+// Readiness is public, so the library is written to at most this often however often it's asked
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+// A check that takes longer than this counts as failed, load balancers don't wait long
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        Self { name, ok: result.is_ok(), detail: result.err() }
+    }
+}
+
+async fn probe_database(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), String> {
+    match timeout(CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {:?}", CHECK_TIMEOUT)),
+    }
+}
+
+// Outcome of the last library write check
+#[derive(Default)]
+pub struct LibraryProbe {
+    last: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl LibraryProbe {
+    // Holding the lock through the write keeps probes from overlapping on the one file
+    async fn check(&self, library: &Path) -> Result<(), String> {
+        let mut last = self.last.lock().await;
+        if let Some((checked, result)) = last.as_ref()
+            && checked.elapsed() < PROBE_INTERVAL
+        {
+            return result.clone();
+        }
+        let result = probe_library(library).await;
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+async fn probe_library(library: &Path) -> Result<(), String> {
+    let probe = library.join(PROBE_FILE);
+    let result = timeout(CHECK_TIMEOUT, async {
+        write(&probe, b"ok").await?;
+        remove_file(&probe).await
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("{} isn't writable: {}", library.display(), e)),
+        Err(_) => Err(format!("writing to {} took longer than {:?}", library.display(), CHECK_TIMEOUT)),
+    }
+}
+
+/// Liveness and readiness
+impl FileCache {
+    /// Whether the server can take uploads: the database answers, the library takes writes and has room, and the background routines are up
+    pub async fn readiness(&self) -> ReadinessReport {
+        let stopped: Vec<&str> = self.task_health().tasks.into_iter().filter(|task| !task.running).map(|task| task.name).collect();
+        let tasks = match stopped.is_empty() {
+            true => Ok(()),
+            false => Err(format!("waiting to restart: {}", stopped.join(", "))),
+        };
+        let checks = vec![
+            Check::new("database", probe_database(&self.pool).await),
+            Check::new("library", self.library_probe.check(&self.library).await),
+            Check::new("space", self.disk.check_room(&self.library, &self.cache_settings)),
+            Check::new("background", tasks),
+        ];
+        ReadinessReport {
+            ready: !self.shutdown.is_cancelled() && checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use super::super::crypto::{self, CHUNK_SIZE, CryptoError, DataKey, SEALED_CHUNK_SIZE, TAG_SIZE};
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::io::{self, SeekFrom};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+const READ_SIZE: u64 = 64 * 1024;
+
+// A file on disk which may or may not be encrypted, read lazily
+pub struct BlobReader {
+    file: File,
+    key: Option<DataKey>,
+    sealed_len: u64,
+}
+
+struct ReadState {
+    file: File,
+    key: Option<DataKey>,
+    sealed_len: u64,
+    position: u64,
+    end: u64,
+    seeked: bool,
+}
+
+impl BlobReader {
+    pub(in super::super) async fn open(file: File, key: Option<DataKey>) -> io::Result<Self> {
+        let sealed_len = file.metadata().await?.len();
+        // Even an empty file has a tag, a shorter blob was truncated and would otherwise stream as empty
+        if key.is_some() && sealed_len < TAG_SIZE as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, CryptoError::Decryption.to_string()));
+        }
+        Ok(Self { file, key, sealed_len })
+    }
+
+    /// Length of the content as the client sees it
+    pub fn len(&self) -> u64 {
+        match self.key {
+            Some(_) => crypto::plaintext_len(self.sealed_len),
+            None => self.sealed_len,
+        }
+    }
+
+    /// Streams the content in `start..end`
+    pub fn into_stream(self, start: u64, end: u64) -> BoxStream<'static, io::Result<Bytes>> {
+        let state = ReadState {
+            end: end.min(self.len()),
+            file: self.file,
+            key: self.key,
+            sealed_len: self.sealed_len,
+            position: start,
+            seeked: false,
+        };
+        stream::try_unfold(state, |mut state| async move {
+            if state.position >= state.end {
+                return Ok(None);
+            }
+            let chunk = match state.key.clone() {
+                Some(key) => state.read_sealed(&key).await?,
+                None => state.read_plain().await?,
+            };
+            state.position += chunk.len() as u64;
+            Ok(Some((chunk, state)))
+        })
+        .boxed()
+    }
+}
+
+impl ReadState {
+    async fn read_plain(&mut self) -> io::Result<Bytes> {
+        if !self.seeked {
+            self.file.seek(SeekFrom::Start(self.position)).await?;
+            self.seeked = true;
+        }
+        let mut buf = vec![0u8; READ_SIZE.min(self.end - self.position) as usize];
+        let read = self.file.read(&mut buf).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.truncate(read);
+        Ok(buf.into())
+    }
+
+    // Decrypts the chunk holding the current position and returns the part of it that was asked for
+    async fn read_sealed(&mut self, key: &DataKey) -> io::Result<Bytes> {
+        let index = self.position / CHUNK_SIZE as u64;
+        let offset = (self.position % CHUNK_SIZE as u64) as usize;
+        let sealed_start = index * SEALED_CHUNK_SIZE as u64;
+        if !self.seeked {
+            self.file.seek(SeekFrom::Start(sealed_start)).await?;
+            self.seeked = true;
+        }
+
+        let sealed_size = (SEALED_CHUNK_SIZE as u64).min(self.sealed_len - sealed_start) as usize;
+        let mut sealed = vec![0u8; sealed_size];
+        self.file.read_exact(&mut sealed).await?;
+
+        let last = index + 1 == crypto::chunk_count(self.sealed_len);
+        let plain = key.decrypt_chunk(index, last, &sealed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let wanted = (self.end - self.position) as usize;
+        let end = plain.len().min(offset + wanted);
+        if offset >= end {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Bytes::copy_from_slice(&plain[offset..end]))
+    }
+}
@@ -1,3 +1,4 @@
+pub(super) mod blob;
 pub(super) mod read;
 pub(super) mod write;
 
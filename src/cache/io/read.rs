@@ -1,36 +1,56 @@
 use crate::{cache::entry::CacheEntry, signal};
 
 use super::super::core::{FileCache, FileCacheError, SignalAction};
+use super::super::crypto::DataKey;
+use super::blob::BlobReader;
 use bytes::Bytes;
 use log::{debug, error};
-use std::path::PathBuf;
-use tokio::{
-    fs::{File, read},
-    io::BufReader,
-};
+use std::path::Path;
+use tokio::fs::{File, read, try_exists};
 
 pub enum FileContent {
     InMemory(Bytes),
-    OnDisk(BufReader<File>),
+    OnDisk(BlobReader),
 }
 
 impl FileCache {
     // Disk -> data
-    pub async fn fetch_to_memory(library: &PathBuf, filename: &str) -> Option<Bytes> {
+    pub async fn fetch_to_memory(library: &Path, filename: &str, key: Option<DataKey>) -> Option<Bytes> {
         let file_path = library.join(filename);
-        let data = read(&file_path).await.ok()?.into();
-        Some(data)
+        let data = read(&file_path).await.ok()?;
+        match key {
+            Some(key) => match tokio::task::spawn_blocking(move || key.decrypt(&data)).await {
+                Ok(Ok(plain)) => Some(plain.into()),
+                _ => {
+                    error!("Failed to decrypt {}", filename);
+                    None
+                }
+            },
+            None => Some(data.into()),
+        }
     }
 
-    pub async fn fetch_reader(library: &PathBuf, filename: &str) -> Option<BufReader<File>> {
+    pub async fn fetch_reader(library: &Path, filename: &str, key: Option<DataKey>) -> Option<BlobReader> {
         let file_path = library.join(filename);
         let file = File::open(file_path).await.ok()?;
-        Some(BufReader::new(file))
+        BlobReader::open(file, key).await.ok()
+    }
+
+    // Unwraps the data key of an encrypted entry
+    fn data_key(&self, entry: &CacheEntry) -> Result<Option<DataKey>, FileCacheError> {
+        let Some(wrapped) = &entry.wrapped_key else {
+            return Ok(None);
+        };
+        let Some(keyring) = &self.cache_settings.keyring else {
+            error!("Entry is encrypted but no master key is configured");
+            return Err(FileCacheError::KeyUnavailable);
+        };
+        keyring.unwrap(wrapped).map(Some).map_err(FileCacheError::Encryption)
     }
 
     pub async fn fetch_file(&self, uuid: &str) -> Result<(String, FileContent), FileCacheError> {
         // Cache hit route:
-        let (size, filename) = {
+        let (size, filename, key) = {
             let cache = self.cache.read().await;
             match cache.get(uuid) {
                 Some(entry) => {
@@ -41,6 +61,9 @@ impl FileCache {
                         signal!(self, uuid, SignalAction::Delete);
                         return Err(FileCacheError::NotFound);
                     }
+                    if let Some(available_from) = entry.available_from() {
+                        return Err(FileCacheError::NotYetAvailable(available_from));
+                    }
 
                     // Cache hit and we found the item in memory
                     if let Some(data) = &entry.data {
@@ -48,9 +71,11 @@ impl FileCache {
                         // Signal to Db that it's been accessed
                         signal!(self, uuid, SignalAction::Accessed);
                         // Return data
-                        return Ok((entry.upload_name.to_string(), FileContent::InMemory(data.clone())));
+                        let content = FileContent::InMemory(data.clone());
+                        self.counters.record_read(&content, true);
+                        return Ok((entry.upload_name.to_string(), content));
                     }
-                    (entry.len, entry.upload_name.to_string())
+                    (entry.len, entry.upload_name.to_string(), self.data_key(entry)?)
                 }
                 None => return Err(FileCacheError::NotFound),
             }
@@ -66,7 +91,7 @@ impl FileCache {
         if space_left {
             debug!("Cache miss but enough space to load to memory");
             // Cache miss route
-            if let Some(data) = Self::fetch_to_memory(&self.library, &uuid).await {
+            if let Some(data) = Self::fetch_to_memory(&self.library, uuid, key).await {
                 let mut cache = self.cache.write().await;
                 if let Some(entry) = cache.get_mut(uuid) {
                     signal!(self, &uuid, SignalAction::Accessed);
@@ -77,32 +102,57 @@ impl FileCache {
                         let mut mem_rw = self.cache_mem.write().await;
                         mem_rw.free(size as usize);
 
-                        return Ok((filename, FileContent::InMemory(d.clone())));
+                        let content = FileContent::InMemory(d.clone());
+                        self.counters.record_read(&content, false);
+                        return Ok((filename, content));
                     }
 
                     entry.update(data.clone());
-                    return Ok((filename, FileContent::InMemory(data)));
+                    let content = FileContent::InMemory(data);
+                    self.counters.record_read(&content, false);
+                    return Ok((filename, content));
                 }
             }
             // Ensure we don't magically force bloat into the cache size param
-            let mut mem_rw = self.cache_mem.write().await;
-            mem_rw.free(size as usize);
+            {
+                let mut mem_rw = self.cache_mem.write().await;
+                mem_rw.free(size as usize);
+            }
+            // Unreadable isn't the same as gone, decryption failures keep their entry
+            if !try_exists(self.library.join(uuid)).await.unwrap_or(true) {
+                self.forget_missing(uuid).await;
+                return Err(FileCacheError::BackingFileMissing);
+            }
             return Err(FileCacheError::NotFound);
         } else {
             // We can't spare the memory so instead we return a reader object
-            if let Some(reader) = Self::fetch_reader(&self.library, &uuid).await {
+            if let Some(reader) = Self::fetch_reader(&self.library, uuid, key).await {
                 debug!("Cache miss and not enough ram, returning reader");
-                return Ok((filename, FileContent::OnDisk(reader)));
+                // Streamed reads count too, otherwise large files always show zero reads and are never burned
+                // The open handle keeps the blob readable even if this read burns it
+                signal!(self, uuid, SignalAction::Accessed);
+                let content = FileContent::OnDisk(reader);
+                self.counters.record_read(&content, false);
+                return Ok((filename, content));
             }
         }
 
-        error!("Backed file is missing despite entry being present in database ");
-        // We want to probaly drop the entry from the cache if we land in this branch
-        Err(FileCacheError::BackingFileMissing)
+        if !try_exists(self.library.join(uuid)).await.unwrap_or(true) {
+            self.forget_missing(uuid).await;
+            return Err(FileCacheError::BackingFileMissing);
+        }
+        error!("Unable to open the blob of {}", uuid);
+        Err(FileCacheError::NotFound)
+    }
+
+    // Peeks at an entry without counting it as a read
+    pub async fn fetch_entry(&self, uuid: &str) -> Option<CacheEntry> {
+        let lock = self.cache.read().await;
+        lock.get(uuid).filter(|entry| !entry.is_expired()).cloned()
     }
 
     pub async fn fetch_entries(&self) -> Vec<CacheEntry> {
         let lock = self.cache.read().await;
-        lock.iter().map(|(_uuid, entry)| (*entry).clone()).collect()
+        lock.values().map(|entry| entry.masked()).collect()
     }
 }
@@ -1,33 +1,58 @@
-use crate::{cache::entry::FileOptions, signal};
+use crate::{
+    cache::entry::{FileChanges, FileOptions},
+    signal,
+};
 
 use super::super::{
     core::{FileCache, FileCacheError, SignalAction},
+    crypto::DataKey,
     entry::CacheEntry,
+    expiry::{DurationValue, Expiry, parse_timestamp},
     instant_to_datetime,
 };
+use crate::users::Role;
+use chrono::{DateTime, Utc};
 use log::{debug, error};
+use sha2::{Digest, Sha256};
+use sqlx::SqliteExecutor;
 use std::io;
-use std::path::PathBuf;
+use std::path::Path;
 use tokio::fs::remove_file;
 use tokio::fs::write;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use uuid::Uuid;
 
+const MAX_METADATA_SIZE: usize = 4096;
+
+// What the uploader gets back
+pub struct StoredUpload {
+    pub uuid: String,
+    // Lets the uploader manage the file without an account
+    pub deletion_token: String,
+    // After the TTL policy and the retention curve
+    pub expiration: DateTime<Utc>,
+}
+
 /// Write
 impl FileCache {
-    pub(in super::super) async fn delete_file(library: &PathBuf, uuid: &str) -> Result<(), io::Error> {
+    pub(in super::super) async fn delete_file(library: &Path, uuid: &str) -> Result<(), io::Error> {
         let filepath = library.join(uuid);
         debug!("Deleting file: {}", filepath.to_str().unwrap_or("<Unable to display nonunicode path>"));
         remove_file(filepath).await
     }
 
-    pub(in super::super) async fn drop_item(uuid: &str, library: &PathBuf, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), FileCacheError> {
-        Self::delete_file(library, uuid).await.map_err(|e| FileCacheError::IoError(e))?;
-        Self::delete_from_db(pool, uuid).await.map_err(|e| FileCacheError::DbError(e))?;
+    pub(in super::super) async fn drop_item(uuid: &str, library: &Path, pool: impl SqliteExecutor<'_>) -> Result<(), FileCacheError> {
+        // A blob that's already gone shouldn't keep its row alive
+        match Self::delete_file(library, uuid).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(FileCacheError::IoError(e)),
+            _ => {}
+        }
+        Self::remove_sidecar(library, uuid).await.map_err(FileCacheError::IoError)?;
+        Self::delete_from_db(pool, uuid).await.map_err(FileCacheError::DbError)?;
         Ok(())
     }
 
-    pub async fn delete_from_db(pool: &sqlx::Pool<sqlx::Sqlite>, uuid: &str) -> Result<(), sqlx::Error> {
+    pub async fn delete_from_db(pool: impl SqliteExecutor<'_>, uuid: &str) -> Result<(), sqlx::Error> {
         debug!("Deleting {} from persistent database", uuid);
         sqlx::query(
             r#"
@@ -42,36 +67,171 @@ impl FileCache {
         Ok(())
     }
 
-    pub async fn push_to_db(pool: &sqlx::Pool<sqlx::Sqlite>, uuid: &str, entry: &CacheEntry) -> Result<(), sqlx::Error> {
+    pub async fn push_to_db(pool: impl SqliteExecutor<'_>, uuid: &str, entry: &CacheEntry) -> Result<(), sqlx::Error> {
         let expiration_utc = instant_to_datetime(&entry.expiration);
 
         sqlx::query(
             r#"
-        INSERT INTO cache (uuid, filename, expiration_utc, burn_after_read, read_count, file_size)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        INSERT INTO cache (uuid, filename, expiration_utc, burn_after_read, read_count, file_size, private, password_hash, data_key, e2e, metadata, owner, size_bytes, created_utc, deletion_token, max_downloads, renew_until_utc, available_from_utc, blob_sha256)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)
         "#,
         )
         .bind(uuid)
         .bind(&entry.upload_name)
         .bind(expiration_utc)
-        .bind(&entry.burn_after_read)
+        .bind(entry.burn_after_read)
         .bind(entry.read_count)
         .bind(entry.len)
+        .bind(entry.private)
+        .bind(&entry.password_hash)
+        .bind(&entry.wrapped_key)
+        .bind(entry.e2e)
+        .bind(&entry.metadata)
+        .bind(&entry.owner)
+        .bind(entry.size as i64)
+        .bind(entry.created)
+        .bind(&entry.deletion_token)
+        .bind(entry.max_downloads)
+        .bind(entry.renew_until.as_ref().map(instant_to_datetime))
+        .bind(entry.available_from)
+        .bind(&entry.checksum)
         .execute(pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn upload_file(&self, bytes: Vec<u8>, filename: &str, upload_options: FileOptions) -> Result<String, FileCacheError> {
+    pub async fn update_access(pool: impl SqliteExecutor<'_>, uuid: &str, read_count: i64, expiration: &Instant) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE cache SET read_count = ?, expiration_utc = ? WHERE uuid = ?")
+            .bind(read_count)
+            .bind(instant_to_datetime(expiration))
+            .bind(uuid)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // Applies the TTL policy of the caller's ACL group, `default` is used when nothing was asked for
+    fn resolve_expiry(&self, role: Option<Role>, expires_in: Option<&DurationValue>, expires_at: Option<&str>, default: Option<Duration>) -> Result<Option<Instant>, FileCacheError> {
+        let requested = Expiry::from_options(expires_in, expires_at).map_err(FileCacheError::InvalidExpiry)?;
+        match (requested, default) {
+            (None, None) => Ok(None),
+            (requested, default) => self.cache_settings.ttl_policies.resolve(role, requested, default.unwrap_or_default()).map(Some).map_err(FileCacheError::InvalidExpiry),
+        }
+    }
+
+    // Big files don't get to live as long, the curve wins over whatever was asked for
+    fn cap_retention(&self, expiration: Instant, size: u64) -> Instant {
+        match &self.cache_settings.retention {
+            Some(curve) => expiration.min(Instant::now() + curve.max_lifetime(size)),
+            None => expiration,
+        }
+    }
+
+    // Times in the past mean the file is available right away
+    fn resolve_publication(available_from: Option<&str>) -> Result<Option<DateTime<Utc>>, FileCacheError> {
+        let Some(raw) = available_from else {
+            return Ok(None);
+        };
+        let available_from = parse_timestamp(raw, "available_from").map_err(FileCacheError::InvalidExpiry)?;
+        Ok(Some(available_from).filter(|from| *from > Utc::now()))
+    }
+
+    /// Stores an upload, returns its uuid, the token which lets the uploader manage it and the effective expiry
+    pub async fn upload_file(&self, bytes: Vec<u8>, filename: &str, upload_options: FileOptions, owner: &str, role: Option<Role>) -> Result<StoredUpload, FileCacheError> {
+        // Nothing queued from here on would reach the database
+        if self.shutdown.is_cancelled() {
+            return Err(FileCacheError::ShuttingDown);
+        }
+        // Settle the expiry first, a bad value shouldn't cost a write
+        let expiration = self.resolve_expiry(role, upload_options.expires_in.as_ref(), upload_options.expires_at.as_deref(), Some(self.cache_settings.on_disk_ttl))?;
+        let expiration = expiration.map(|expiration| self.cap_retention(expiration, bytes.len() as u64));
+
+        // Either the countdown starts at publication, or the file has to outlive its publication time
+        let available_from = Self::resolve_publication(upload_options.available_from.as_deref())?;
+        let expiration = match (expiration, available_from) {
+            (Some(expiration), Some(from)) if upload_options.expire_after_publication.unwrap_or(false) => {
+                let delay = (from - Utc::now()).to_std().unwrap_or_default();
+                Some(expiration.checked_add(delay).ok_or_else(|| FileCacheError::InvalidExpiry("available_from is too far away".to_string()))?)
+            }
+            (Some(expiration), Some(from)) if instant_to_datetime(&expiration) <= from => return Err(FileCacheError::InvalidExpiry("the file would expire before it becomes available".to_string())),
+            (expiration, _) => expiration,
+        };
+
+        let sliding = upload_options.sliding.unwrap_or(false);
+        if sliding && self.cache_settings.sliding_window.is_none() {
+            return Err(FileCacheError::InvalidOptions("sliding expiry is not enabled on this server"));
+        }
+
+        // The plaintext name of an end-to-end encrypted file never reaches the server's storage
+        let e2e = upload_options.e2e.unwrap_or(false);
+        let (filename, metadata) = match (e2e, upload_options.metadata) {
+            (true, Some(metadata)) if metadata.len() <= MAX_METADATA_SIZE => ("", Some(metadata)),
+            (true, Some(_)) => return Err(FileCacheError::InvalidOptions("encrypted metadata is too large")),
+            (true, None) => return Err(FileCacheError::InvalidOptions("end-to-end uploads need encrypted metadata")),
+            (false, _) => (filename, None),
+        };
+
+        // Hash before touching the disk so a failure doesn't leave an orphan behind
+        let password_hash = match upload_options.password.filter(|p| !p.is_empty()) {
+            Some(password) => match tokio::task::spawn_blocking(move || CacheEntry::hash_password(&password)).await {
+                Ok(Some(hash)) => Some(hash),
+                _ => return Err(FileCacheError::PasswordHashing),
+            },
+            None => None,
+        };
+
+        // can panic
+        let len = bytes.len() as i64;
+
+        // Quotas are checked up front and again once the entry goes in as concurrent uploads might have raced us
+        {
+            let cache = self.cache.read().await;
+            self.check_live_quota(&cache, owner, len as u64)?;
+        }
+        self.reserve_daily_quota(owner, len as u64).await?;
+        if let Err(e) = self.ensure_space(len as u64).await {
+            self.release_daily_quota(owner, len as u64).await;
+            return Err(e);
+        }
+
         // Generate UUID
         let entry_uuid = Uuid::new_v4().to_string();
         let filepath = self.library.join(&entry_uuid);
-        // can panic
-        let len = bytes.len() as i64;
+
+        // Seal the blob with a fresh data key if encryption at rest is on
+        let (bytes, wrapped_key) = match (&self.cache_settings.keyring, self.cache_settings.encrypt_uploads) {
+            (Some(keyring), true) => {
+                let data_key = DataKey::generate();
+                let wrapped = keyring.current.wrap(&data_key);
+                match tokio::task::spawn_blocking(move || data_key.encrypt(&bytes)).await {
+                    Ok(sealed) => (sealed, Some(wrapped)),
+                    Err(e) => {
+                        self.release_daily_quota(owner, len as u64).await;
+                        return Err(FileCacheError::IoError(std::io::Error::other(e)));
+                    }
+                }
+            }
+            _ => (bytes, None),
+        };
+
+        // The scrubber verifies what's on disk, so the checksum covers the sealed bytes
+        let (bytes, checksum) = match tokio::task::spawn_blocking(move || {
+            let checksum = hex::encode(Sha256::digest(&bytes));
+            (bytes, checksum)
+        })
+        .await
+        {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                self.release_daily_quota(owner, len as u64).await;
+                return Err(FileCacheError::IoError(std::io::Error::other(e)));
+            }
+        };
 
         // Write the file
         if let Err(e) = write(&filepath, bytes).await {
+            self.release_daily_quota(owner, len as u64).await;
             if e.kind() == std::io::ErrorKind::StorageFull {
                 return Err(FileCacheError::NoSpaceLeftOnDevice);
             }
@@ -79,18 +239,99 @@ impl FileCache {
         }
 
         // Extract entry specific settings
-        let ttl = upload_options.expires_in.map(|s| Duration::from_secs(s)).unwrap_or(self.cache_settings.on_disk_ttl);
         let burn_after_read = upload_options.burn_after_read.unwrap_or(false);
+        let private = upload_options.private.unwrap_or(false);
 
         // This can panic
-        let entry = CacheEntry::new(filename, None, len, burn_after_read, ttl);
+        let mut entry = CacheEntry::new(filename, None, len, burn_after_read, private, Duration::ZERO);
+        if let Some(expiration) = expiration {
+            entry.expiration = expiration;
+        }
+        entry.password_hash = password_hash;
+        entry.wrapped_key = wrapped_key;
+        entry.checksum = Some(checksum);
+        entry.e2e = e2e;
+        entry.metadata = metadata;
+        entry.owner = Some(owner.to_string());
+        entry.available_from = available_from;
+        entry.max_downloads = upload_options.max_downloads.filter(|max| *max > 0).map(|max| max as i64);
+        let (deletion_token, token_hash) = CacheEntry::new_deletion_token();
+        entry.deletion_token = Some(token_hash);
+        // The hard cap is subject to the retention curve like any other lifetime
+        if sliding {
+            let renew_until = self.cap_retention(Instant::now() + self.cache_settings.sliding_hard_cap, len as u64);
+            entry.renew_until = Some(renew_until.max(entry.expiration));
+        }
+        let expiration = instant_to_datetime(&entry.expiration);
 
         {
             let mut cache = self.cache.write().await;
+            if let Err(e) = self.check_live_quota(&cache, owner, len as u64) {
+                drop(cache);
+                self.release_daily_quota(owner, len as u64).await;
+                if let Err(e) = Self::delete_file(&self.library, &entry_uuid).await {
+                    error!("Error deleting file: {}", e)
+                }
+                return Err(e);
+            }
             cache.insert(entry_uuid.to_string(), entry);
         }
 
         signal!(self, entry_uuid, SignalAction::NewFile);
-        Ok(entry_uuid)
+        Ok(StoredUpload { uuid: entry_uuid, deletion_token, expiration })
+    }
+
+    /// Applies changes to a live upload, in memory and in the database
+    pub async fn modify_file(&self, uuid: &str, changes: FileChanges, role: Option<Role>) -> Result<(), FileCacheError> {
+        let expiration = self.resolve_expiry(role, changes.expires_in.as_ref(), changes.expires_at.as_deref(), None)?;
+        let available_from = match changes.available_from.as_deref() {
+            Some(raw) => Some(Self::resolve_publication(Some(raw))?),
+            None => None,
+        };
+
+        let entry = {
+            let mut cache = self.cache.write().await;
+            let Some(entry) = cache.get_mut(uuid).filter(|entry| !entry.is_expired()) else {
+                return Err(FileCacheError::NotFound);
+            };
+            if changes.filename.is_some() && entry.e2e {
+                return Err(FileCacheError::InvalidOptions("end-to-end encrypted files have no server side filename"));
+            }
+
+            // Worked out on the side so a rejected change leaves the entry untouched
+            let new_expiration = expiration.map(|expiration| self.cap_retention(expiration, entry.size)).unwrap_or(entry.expiration);
+            let new_available_from = available_from.unwrap_or(entry.available_from);
+            if let Some(from) = new_available_from
+                && instant_to_datetime(&new_expiration) <= from
+            {
+                return Err(FileCacheError::InvalidExpiry("the file would expire before it becomes available".to_string()));
+            }
+
+            entry.expiration = new_expiration;
+            entry.available_from = new_available_from;
+            if let Some(burn_after_read) = changes.burn_after_read {
+                entry.burn_after_read = burn_after_read;
+            }
+            if let Some(max_downloads) = changes.max_downloads {
+                entry.max_downloads = Some(max_downloads as i64).filter(|max| *max > 0);
+            }
+            if let Some(filename) = changes.filename {
+                entry.upload_name = filename;
+            }
+            entry.clone()
+        };
+
+        sqlx::query("UPDATE cache SET expiration_utc = ?, burn_after_read = ?, max_downloads = ?, filename = ?, available_from_utc = ? WHERE uuid = ?")
+            .bind(instant_to_datetime(&entry.expiration))
+            .bind(entry.burn_after_read)
+            .bind(entry.max_downloads)
+            .bind(&entry.upload_name)
+            .bind(entry.available_from)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+        self.save_sidecar(uuid).await;
+        Ok(())
     }
 }
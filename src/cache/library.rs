@@ -0,0 +1,76 @@
+use super::{core::FileCache, database::DATABASE_FILE, sidecar::sidecar_of};
+use log::{debug, error, info, warn};
+use std::io;
+use std::path::Path;
+use tokio::fs::{create_dir_all, read_dir, try_exists, write};
+use uuid::Uuid;
+
+// Marks a directory as ours, nothing is ever deleted from a directory without it
+const MARKER: &str = ".korvatunturi-library";
+
+// Blobs are stored under their hyphenated lowercase uuid
+pub(super) fn is_blob_name(name: &str) -> bool {
+    Uuid::try_parse(name).is_ok_and(|uuid| uuid.to_string() == name)
+}
+
+/// Library directory
+impl FileCache {
+    /// Creates the library if needed and claims it when empty, returns whether it carries our marker
+    pub(super) async fn prepare_library(library: &Path) -> io::Result<bool> {
+        create_dir_all(library).await?;
+        let marker = library.join(MARKER);
+        if try_exists(&marker).await? {
+            return Ok(true);
+        }
+
+        // Only an empty directory is safe to claim, anything else might belong to someone else. Our own database may already be there
+        let mut paths = read_dir(library).await?;
+        let mut empty = true;
+        while let Some(file) = paths.next_entry().await? {
+            if !file.file_name().to_str().is_some_and(|name| name.starts_with(DATABASE_FILE)) {
+                empty = false;
+                break;
+            }
+        }
+        if !empty {
+            warn!("{} has no {} marker, leaving its contents alone. Create the marker by hand if the directory only holds uploads", library.display(), MARKER);
+            return Ok(false);
+        }
+        info!("Claiming {} as the library", library.display());
+        write(&marker, b"This directory is managed by korvatunturi, files in it may be deleted at any time.\n").await?;
+        Ok(true)
+    }
+
+    /// Removes blobs and sidecars nothing refers to and no sidecar can restore, only ever touches uuid named files, returns how many were found
+    pub(super) async fn remove_orphans(library: &Path, known: impl Fn(&str) -> bool, dry_run: bool) -> io::Result<usize> {
+        let mut orphans = 0;
+        let mut paths = read_dir(library).await?;
+        while let Some(file) = paths.next_entry().await? {
+            let Some(filename) = file.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            // Sidecars go with their blob
+            let uuid = sidecar_of(&filename).unwrap_or(&filename);
+            if !is_blob_name(uuid) || !file.file_type().await?.is_file() {
+                debug!("Skipping {} in the library", filename);
+                continue;
+            }
+            if known(uuid) {
+                continue;
+            }
+            // Left for `rebuild-index`, the database may have been lost
+            if Self::is_restorable(library, uuid).await {
+                warn!("{} isn't indexed but its sidecar can restore it, leaving it alone", filename);
+                continue;
+            }
+
+            orphans += 1;
+            if dry_run {
+                info!("Would remove orphaned file {}", filename);
+            } else if let Err(e) = Self::delete_file(library, &filename).await {
+                error!("Error deleting file: {}", e)
+            }
+        }
+        Ok(orphans)
+    }
+}
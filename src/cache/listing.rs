@@ -0,0 +1,254 @@
+use super::core::{FileCache, FileCacheError};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, QueryBuilder, Sqlite};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Created,
+    Expires,
+    Name,
+    Size,
+    Reads,
+}
+
+impl SortKey {
+    // Rows written by older versions have no exact size or creation time
+    fn expression(&self) -> &'static str {
+        match self {
+            SortKey::Created => "COALESCE(created_utc, '')",
+            SortKey::Expires => "expiration_utc",
+            SortKey::Name => "filename",
+            SortKey::Size => "COALESCE(size_bytes, file_size * 1000)",
+            SortKey::Reads => "read_count",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Created => "created",
+            SortKey::Expires => "expires",
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Reads => "reads",
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, SortKey::Size | SortKey::Reads)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct FileFilter {
+    // Case insensitive substring of the filename
+    pub name: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub expires_before: Option<DateTime<Utc>>,
+    pub expires_after: Option<DateTime<Utc>>,
+    pub burn_after_read: Option<bool>,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<u32>,
+    // `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+#[derive(FromRow)]
+struct FileSummaryRow {
+    uuid: String,
+    filename: String,
+    size: i64,
+    created_utc: Option<DateTime<Utc>>,
+    expiration_utc: DateTime<Utc>,
+    read_count: i64,
+    burn_after_read: i8,
+    private: i8,
+    e2e: i8,
+    password_protected: i8,
+    owner: Option<String>,
+    available_from_utc: Option<DateTime<Utc>>,
+    sort_key: String,
+}
+
+#[derive(Serialize)]
+pub struct FileSummary {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub created: Option<DateTime<Utc>>,
+    pub expires: DateTime<Utc>,
+    pub read_count: i64,
+    pub burn_after_read: bool,
+    pub private: bool,
+    pub e2e: bool,
+    pub password_protected: bool,
+    pub owner: Option<String>,
+    pub available_from: Option<DateTime<Utc>>,
+}
+
+impl From<FileSummaryRow> for FileSummary {
+    fn from(row: FileSummaryRow) -> Self {
+        let e2e = row.e2e == 1;
+        Self {
+            id: row.uuid,
+            // Same masking as `CacheEntry::masked`
+            filename: if e2e { "<end-to-end encrypted>".to_string() } else { row.filename },
+            size: row.size.max(0) as u64,
+            created: row.created_utc,
+            expires: row.expiration_utc,
+            read_count: row.read_count,
+            burn_after_read: row.burn_after_read == 1,
+            private: row.private == 1,
+            e2e,
+            password_protected: row.password_protected == 1,
+            owner: row.owner,
+            available_from: row.available_from_utc,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FilePage {
+    pub files: Vec<FileSummary>,
+    pub next_cursor: Option<String>,
+}
+
+// The cursor holds the sort value and uuid of the last row so pages stay stable while files come and go
+fn encode_cursor(sort_key: &str, uuid: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}\n{}", sort_key, uuid))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (sort_key, uuid) = decoded.rsplit_once('\n')?;
+    Some((sort_key.to_string(), uuid.to_string()))
+}
+
+/// Listing
+impl FileCache {
+    pub async fn list_files(&self, filter: &FileFilter) -> Result<FilePage, FileCacheError> {
+        let sort = filter.sort.expression();
+        let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(format!(
+            r#"
+            SELECT uuid, filename, COALESCE(size_bytes, file_size * 1000) AS size, created_utc, expiration_utc, read_count, burn_after_read, private, e2e,
+                password_hash IS NOT NULL AS password_protected, owner, available_from_utc, CAST({} AS TEXT) AS sort_key
+            FROM cache
+            WHERE deleted_utc IS NULL AND quarantined_utc IS NULL
+            "#,
+            sort
+        ));
+
+        if let Some(name) = &filter.name {
+            query.push(" AND instr(lower(filename), lower(").push_bind(name).push(")) > 0");
+        }
+        if let Some(min_size) = filter.min_size {
+            query.push(" AND COALESCE(size_bytes, file_size * 1000) >= ").push_bind(min_size as i64);
+        }
+        if let Some(max_size) = filter.max_size {
+            query.push(" AND COALESCE(size_bytes, file_size * 1000) <= ").push_bind(max_size as i64);
+        }
+        if let Some(before) = filter.expires_before {
+            query.push(" AND expiration_utc < ").push_bind(before);
+        }
+        if let Some(after) = filter.expires_after {
+            query.push(" AND expiration_utc > ").push_bind(after);
+        }
+        if let Some(burn_after_read) = filter.burn_after_read {
+            query.push(" AND burn_after_read = ").push_bind(burn_after_read);
+        }
+        if let Some(owner) = &filter.owner {
+            query.push(" AND owner = ").push_bind(owner);
+        }
+
+        let (direction, comparison) = match filter.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        if let Some(cursor) = &filter.cursor {
+            let Some((sort_key, uuid)) = decode_cursor(cursor) else {
+                return Err(FileCacheError::InvalidOptions("invalid cursor"));
+            };
+            query.push(format!(" AND ({} {} ", sort, comparison));
+            if filter.sort.is_numeric() {
+                let value: i64 = sort_key.parse().map_err(|_| FileCacheError::InvalidOptions("invalid cursor"))?;
+                query.push_bind(value).push(format!(" OR ({} = ", sort)).push_bind(value);
+            } else {
+                query.push_bind(sort_key.clone()).push(format!(" OR ({} = ", sort)).push_bind(sort_key);
+            }
+            query.push(format!(" AND uuid {} ", comparison)).push_bind(uuid).push("))");
+        }
+
+        // One extra row tells whether there is a next page
+        query.push(format!(" ORDER BY {} {}, uuid {} LIMIT ", sort, direction, direction)).push_bind(limit as i64 + 1);
+
+        let mut rows: Vec<FileSummaryRow> = query.build_query_as().fetch_all(&self.pool).await.map_err(FileCacheError::DbError)?;
+        let next_cursor = match rows.len() > limit as usize {
+            true => {
+                rows.truncate(limit as usize);
+                rows.last().map(|row| encode_cursor(&row.sort_key, &row.uuid))
+            }
+            false => None,
+        };
+
+        Ok(FilePage {
+            files: rows.into_iter().map(FileSummary::from).collect(),
+            next_cursor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = encode_cursor("2026-10-18T12:00:00Z", "6c31346f-0cad-4fa3-94cc-f994b741902b");
+        assert_eq!(decode_cursor(&cursor), Some(("2026-10-18T12:00:00Z".to_string(), "6c31346f-0cad-4fa3-94cc-f994b741902b".to_string())));
+    }
+
+    #[test]
+    fn cursor_keeps_newlines_in_the_sort_key() {
+        // Filenames are sort keys too and may hold anything
+        let cursor = encode_cursor("first\nsecond\n", "6c31346f-0cad-4fa3-94cc-f994b741902b");
+        assert_eq!(decode_cursor(&cursor), Some(("first\nsecond\n".to_string(), "6c31346f-0cad-4fa3-94cc-f994b741902b".to_string())));
+        assert_eq!(decode_cursor(&encode_cursor("", "id")), Some((String::new(), "id".to_string())));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        assert_eq!(decode_cursor("not base64!"), None);
+        assert_eq!(decode_cursor(&URL_SAFE_NO_PAD.encode("no separator")), None);
+        assert_eq!(decode_cursor(&URL_SAFE_NO_PAD.encode([0xff, b'\n', b'a'])), None);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::flush_entry;
+
+use super::{core::FileCache, instant_to_datetime};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+#[derive(Serialize)]
+pub struct MemoryUsage {
+    pub used_bytes: u64,
+    pub max_bytes: u64,
+    pub cached_files: u64,
+}
+
+/// Admin operations
+impl FileCache {
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        let lock = self.cache.read().await;
+        let (used_bytes, cached_files) = lock.values().filter_map(|entry| entry.data.as_ref()).fold((0, 0), |(bytes, files), data| (bytes + data.len() as u64, files + 1));
+        MemoryUsage {
+            used_bytes,
+            max_bytes: self.cache_settings.max_cache_memory as u64,
+            cached_files,
+        }
+    }
+
+    /// Deletes entries right away, or moves them to the trash, returns how many existed
+    pub async fn delete_files(&self, uuids: &[String]) -> usize {
+        let mut removed = Vec::new();
+        {
+            let mut lock = self.cache.write().await;
+            for uuid in uuids {
+                if let Some(mut entry) = lock.remove(uuid) {
+                    flush_entry!(entry, uuid, Duration::ZERO, self.cache_mem);
+                    removed.push(uuid);
+                }
+            }
+        }
+
+        for uuid in &removed {
+            info!("Deleting {} on request", uuid);
+            if let Err(e) = Self::discard(uuid, &self.library, &self.pool, self.cache_settings.trash_grace).await {
+                warn!("Error dropping file: {:#?}", e)
+            }
+        }
+        removed.len()
+    }
+
+    /// Pushes the expiry of live entries back by `duration`, returns how many were extended
+    pub async fn extend_expiry(&self, uuids: &[String], duration: Duration) -> usize {
+        let extended: Vec<(String, Instant)> = {
+            let mut lock = self.cache.write().await;
+            uuids
+                .iter()
+                .filter_map(|uuid| {
+                    let entry = lock.get_mut(uuid).filter(|entry| !entry.is_expired())?;
+                    entry.expiration += duration;
+                    Some((uuid.clone(), entry.expiration))
+                })
+                .collect()
+        };
+
+        for (uuid, expiration) in &extended {
+            debug!("Extending {} by {:?}", uuid, duration);
+            let result = sqlx::query("UPDATE cache SET expiration_utc = ? WHERE uuid = ?").bind(instant_to_datetime(expiration)).bind(uuid).execute(&self.pool).await;
+            if let Err(e) = result {
+                error!("Failed to save the expiry of {}: {e}", uuid);
+            }
+            self.save_sidecar(uuid).await;
+        }
+        extended.len()
+    }
+
+    /// Flips burn after read on live entries, returns how many were changed
+    pub async fn toggle_burn_after_read(&self, uuids: &[String]) -> usize {
+        let toggled: Vec<(String, bool)> = {
+            let mut lock = self.cache.write().await;
+            uuids
+                .iter()
+                .filter_map(|uuid| {
+                    let entry = lock.get_mut(uuid).filter(|entry| !entry.is_expired())?;
+                    entry.burn_after_read = !entry.burn_after_read;
+                    Some((uuid.clone(), entry.burn_after_read))
+                })
+                .collect()
+        };
+
+        for (uuid, burn_after_read) in &toggled {
+            let result = sqlx::query("UPDATE cache SET burn_after_read = ? WHERE uuid = ?").bind(burn_after_read).bind(uuid).execute(&self.pool).await;
+            if let Err(e) = result {
+                error!("Failed to save burn after read of {}: {e}", uuid);
+            }
+            self.save_sidecar(uuid).await;
+        }
+        toggled.len()
+    }
+}
@@ -1,11 +1,27 @@
+pub mod consistency;
 pub mod core;
+pub mod crypto;
+pub mod database;
+pub mod disk;
 mod entry;
+pub mod expiry;
+pub mod health;
 mod io;
+mod library;
+pub mod listing;
+pub mod manage;
 mod mem;
+pub mod queue;
+pub mod quota;
+pub mod scrub;
 pub mod settings;
+pub mod sidecar;
+pub mod stats;
+pub mod supervisor;
+pub mod trash;
 
 pub use core::FileCache;
-pub use entry::FileOptions;
+pub use entry::{CacheEntry, FileChanges, FileOptions};
 pub use io::FileContent;
 
 use chrono::{DateTime, Utc};
@@ -19,6 +35,11 @@ fn instant_to_datetime(target: &Instant) -> DateTime<Utc> {
     Utc::now() + chrono::Duration::from_std(diff).unwrap_or_default()
 }
 
+fn datetime_to_instant(target: &DateTime<Utc>) -> Instant {
+    let diff = (*target - Utc::now()).to_std().unwrap_or_default();
+    Instant::now() + diff
+}
+
 #[macro_export]
 macro_rules! flush_entry {
     ($entry:expr, $uuid:expr, $ttl:expr, $cache_mem:expr) => {{
@@ -0,0 +1,245 @@
+use crate::flush_entry;
+
+use super::{
+    core::{FileCache, SignalAction},
+    entry::CacheEntry,
+    supervisor::Background,
+};
+use log::{debug, error, warn};
+use serde::Serialize;
+use sqlx::SqliteExecutor;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant, sleep, timeout_at};
+use tokio_util::sync::CancellationToken;
+
+// Slots in the channel between the request handlers and the background routines
+pub(super) const QUEUE_CAPACITY: usize = 3000;
+
+pub(super) type Signal = (String, SignalAction);
+
+// Transactions that fail, say on SQLITE_BUSY during a VACUUM, are retried this often before falling back to single writes
+const COMMIT_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Database side of a signal
+enum Write {
+    Insert(String, Box<CacheEntry>),
+    Access(String, i64, Instant),
+    Discard(String, Option<Duration>),
+}
+
+impl Write {
+    fn uuid(&self) -> &str {
+        match self {
+            Write::Insert(uuid, _) | Write::Access(uuid, ..) | Write::Discard(uuid, _) => uuid,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QueueStats {
+    peak_depth: AtomicUsize,
+    batches: AtomicU64,
+    signals: AtomicU64,
+    failed_batches: AtomicU64,
+    largest_batch: AtomicUsize,
+    last_batch_ms: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct QueueReport {
+    // Signals waiting for the background routines, handlers block once it reaches the capacity
+    pub depth: usize,
+    pub capacity: usize,
+    // Counted since startup
+    pub peak_depth: usize,
+    pub batches: u64,
+    pub signals: u64,
+    pub failed_batches: u64,
+    pub largest_batch: usize,
+    pub last_batch_ms: u64,
+}
+
+impl QueueStats {
+    pub(super) fn record(&self, depth: usize, size: usize, took: Duration, committed: bool) {
+        self.peak_depth.fetch_max(depth, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.signals.fetch_add(size as u64, Ordering::Relaxed);
+        self.largest_batch.fetch_max(size, Ordering::Relaxed);
+        self.last_batch_ms.store(took.as_millis() as u64, Ordering::Relaxed);
+        if !committed {
+            self.failed_batches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Whatever is already queued is taken right away, then waits up to `latency` for the batch to fill. A shutdown cuts the wait short
+pub(super) async fn collect_batch(receiver: &mut mpsc::Receiver<Signal>, first: Signal, max_size: usize, latency: Duration, shutdown: &CancellationToken) -> Vec<Signal> {
+    let mut batch = vec![first];
+    let deadline = Instant::now() + latency;
+    while batch.len() < max_size {
+        let next = select! {
+            _ = shutdown.cancelled() => break,
+            next = timeout_at(deadline, receiver.recv()) => next,
+        };
+        match next {
+            Ok(Some(signal)) => batch.push(signal),
+            _ => break,
+        }
+    }
+    batch
+}
+
+/// Signal queue
+impl FileCache {
+    /// Applies a batch of signals in order, every database write shares one transaction. Returns whether it was committed
+    pub(super) async fn apply_signals(batch: Vec<Signal>, background: &Background) -> bool {
+        // The cache only changes once, the database writes it leads to can be retried
+        let mut writes = Vec::new();
+        for (uuid, action) in batch {
+            Self::apply_signal(uuid, action, background, &mut writes).await;
+        }
+        if writes.is_empty() {
+            return true;
+        }
+
+        let size = writes.len();
+        let mut committed = false;
+        for attempt in 1..=COMMIT_ATTEMPTS {
+            match Self::commit_writes(&writes, &background.pool).await {
+                Ok(()) => {
+                    committed = true;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to write {} changes (attempt {}/{}): {e}", size, attempt, COMMIT_ATTEMPTS);
+                    sleep(RETRY_DELAY * attempt).await;
+                }
+            }
+        }
+        // Better some of them than none
+        if !committed {
+            error!("Giving up on a transaction, writing {} changes one by one", size);
+            for write in &writes {
+                if let Err(e) = Self::apply_write(write, &background.pool).await {
+                    error!("Failed to write a change to {}: {e}", write.uuid());
+                    continue;
+                }
+                Self::finish_write(write, background).await;
+            }
+            return false;
+        }
+        for write in &writes {
+            Self::finish_write(write, background).await;
+        }
+        true
+    }
+
+    async fn commit_writes(writes: &[Write], pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        for write in writes {
+            Self::apply_write(write, &mut *tx).await?;
+        }
+        tx.commit().await
+    }
+
+    async fn apply_write(write: &Write, executor: impl SqliteExecutor<'_>) -> Result<(), sqlx::Error> {
+        match write {
+            Write::Insert(uuid, entry) => Self::push_to_db(executor, uuid, entry).await,
+            Write::Access(uuid, read_count, expiration) => Self::update_access(executor, uuid, *read_count, expiration).await,
+            Write::Discard(uuid, grace) => Self::discard_row(executor, uuid, *grace).await,
+        }
+    }
+
+    // Files only go once their row is out of the way, a failed write never leaves a row without its blob
+    async fn finish_write(write: &Write, background: &Background) {
+        if let Write::Discard(uuid, grace) = write
+            && let Err(e) = Self::discard_files(uuid, &background.library, *grace).await
+        {
+            warn!("Error dropping file: {:#?}", e)
+        }
+    }
+
+    async fn apply_signal(uuid: String, action: SignalAction, background: &Background, writes: &mut Vec<Write>) {
+        let Background {
+            cache,
+            cache_mem,
+            library,
+            settings: cache_settings,
+            counters,
+            ..
+        } = background;
+        match action {
+            // Delete file from database and disk (expired)
+            SignalAction::Delete => {
+                let mut rw_lock = cache.write().await;
+                if let Some(mut entry) = rw_lock.remove(&uuid) {
+                    flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
+                    counters.record_expired(1);
+                    writes.push(Write::Discard(uuid, cache_settings.trash_grace));
+                }
+            }
+            SignalAction::NewFile => {
+                let lock = cache.read().await;
+                if let Some(entry) = lock.get(&uuid)
+                    && !entry.is_expired()
+                {
+                    if let Err(e) = Self::write_sidecar(library, &uuid, entry).await {
+                        error!("Failed to write the sidecar of {}: {e}", uuid);
+                    }
+                    writes.push(Write::Insert(uuid.clone(), Box::new(entry.clone())));
+                }
+            }
+            SignalAction::Accessed => {
+                let mut rw_lock = cache.write().await;
+                let mut expired = false;
+                let mut used_up = false;
+                let mut accessed = None;
+
+                if let Some(entry) = rw_lock.get_mut(&uuid) {
+                    entry.read_count += 1;
+                    expired = entry.is_expired();
+                    // A read arriving after the expiry time doesn't make it a burn
+                    used_up = entry.is_used_up() && entry.expiration >= Instant::now();
+                    if !expired
+                        && let Some(window) = cache_settings.sliding_window
+                        && entry.slide(window)
+                    {
+                        debug!("Sliding the expiry of {} forward", uuid);
+                    }
+                    accessed = Some((entry.read_count, entry.expiration));
+                }
+
+                if expired {
+                    if let Some(mut entry) = rw_lock.remove(&uuid) {
+                        flush_entry!(entry, &uuid, cache_settings.in_memory_ttl, cache_mem);
+                        // Burn after read and download limits are counted apart from expiry by time
+                        match used_up {
+                            true => counters.record_burned(),
+                            false => counters.record_expired(1),
+                        }
+                        writes.push(Write::Discard(uuid, cache_settings.trash_grace));
+                    }
+                } else if let Some((read_count, expiration)) = accessed {
+                    // Keeps the admin listing and sliding expiry in sync
+                    writes.push(Write::Access(uuid, read_count, expiration));
+                }
+            }
+        }
+    }
+
+    pub fn queue_report(&self) -> QueueReport {
+        QueueReport {
+            depth: QUEUE_CAPACITY - self.sync.capacity(),
+            capacity: QUEUE_CAPACITY,
+            peak_depth: self.queue.peak_depth.load(Ordering::Relaxed),
+            batches: self.queue.batches.load(Ordering::Relaxed),
+            signals: self.queue.signals.load(Ordering::Relaxed),
+            failed_batches: self.queue.failed_batches.load(Ordering::Relaxed),
+            largest_batch: self.queue.largest_batch.load(Ordering::Relaxed),
+            last_batch_ms: self.queue.last_batch_ms.load(Ordering::Relaxed),
+        }
+    }
+}
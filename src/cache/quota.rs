@@ -0,0 +1,174 @@
+use super::{
+    core::{FileCache, FileCacheError},
+    entry::CacheEntry,
+};
+use crate::settings::{QuotaConfig, QuotaLimits};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, Copy)]
+pub enum QuotaKind {
+    // A single file bigger than the whole live byte quota
+    FileTooLarge,
+    LiveBytes,
+    LiveFiles,
+    DailyBytes,
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKind::FileTooLarge => write!(f, "file is larger than the storage quota"),
+            QuotaKind::LiveBytes => write!(f, "storage quota exceeded"),
+            QuotaKind::LiveFiles => write!(f, "file count quota exceeded"),
+            QuotaKind::DailyBytes => write!(f, "daily upload quota exceeded"),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Quotas {
+    default: QuotaLimits,
+    overrides: HashMap<String, QuotaLimits>,
+}
+
+impl From<&QuotaConfig> for Quotas {
+    fn from(conf: &QuotaConfig) -> Self {
+        Self {
+            default: conf.default.clone(),
+            overrides: conf.overrides.clone(),
+        }
+    }
+}
+
+impl Quotas {
+    /// Limits of an owner, fields missing from an override fall back to the defaults
+    pub fn limits(&self, owner: &str) -> QuotaLimits {
+        match self.overrides.get(owner) {
+            Some(limits) => QuotaLimits {
+                max_live_bytes: limits.max_live_bytes.or(self.default.max_live_bytes),
+                max_live_files: limits.max_live_files.or(self.default.max_live_files),
+                max_daily_bytes: limits.max_daily_bytes.or(self.default.max_daily_bytes),
+            },
+            None => self.default.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub owner: String,
+    pub live_bytes: u64,
+    pub live_files: u64,
+    pub daily_bytes: u64,
+    pub max_live_bytes: Option<u64>,
+    pub max_live_files: Option<u64>,
+    pub max_daily_bytes: Option<u64>,
+}
+
+// Bytes uploaded per owner on the current UTC day, expired and deleted files still count
+#[derive(Default)]
+pub(super) struct DailyUsage(HashMap<String, (NaiveDate, u64)>);
+
+impl DailyUsage {
+    // Seeded from the entries that survived a restart
+    pub(super) fn from_entries(entries: &HashMap<String, CacheEntry>) -> Self {
+        let today = Utc::now().date_naive();
+        let mut usage = HashMap::new();
+        for entry in entries.values() {
+            if let Some(owner) = &entry.owner
+                && entry.created.is_some_and(|created| created.date_naive() == today)
+            {
+                let (_, bytes) = usage.entry(owner.clone()).or_insert((today, 0));
+                *bytes += entry.size;
+            }
+        }
+        Self(usage)
+    }
+
+    pub(super) fn get(&self, owner: &str) -> u64 {
+        let today = Utc::now().date_naive();
+        match self.0.get(owner) {
+            Some((day, bytes)) if *day == today => *bytes,
+            _ => 0,
+        }
+    }
+
+    pub(super) fn add(&mut self, owner: &str, size: u64) {
+        let today = Utc::now().date_naive();
+        let (day, bytes) = self.0.entry(owner.to_string()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *bytes = 0;
+        }
+        *bytes += size;
+    }
+
+    pub(super) fn sub(&mut self, owner: &str, size: u64) {
+        if let Some((_, bytes)) = self.0.get_mut(owner) {
+            *bytes = bytes.saturating_sub(size);
+        }
+    }
+}
+
+/// Quotas
+impl FileCache {
+    // Live bytes and files held by an owner
+    fn live_usage(entries: &HashMap<String, CacheEntry>, owner: &str) -> (u64, u64) {
+        entries
+            .values()
+            .filter(|entry| !entry.is_expired() && entry.owner.as_deref() == Some(owner))
+            .fold((0, 0), |(bytes, files), entry| (bytes + entry.size, files + 1))
+    }
+
+    // Checks whether `size` more bytes would fit, the caller holds the cache lock
+    pub(super) fn check_live_quota(&self, entries: &HashMap<String, CacheEntry>, owner: &str, size: u64) -> Result<(), FileCacheError> {
+        let limits = self.cache_settings.quotas.limits(owner);
+        if limits.max_live_bytes.is_some_and(|max| size > max) {
+            return Err(FileCacheError::QuotaExceeded(QuotaKind::FileTooLarge));
+        }
+        let (bytes, files) = Self::live_usage(entries, owner);
+        if limits.max_live_files.is_some_and(|max| files + 1 > max) {
+            return Err(FileCacheError::QuotaExceeded(QuotaKind::LiveFiles));
+        }
+        if limits.max_live_bytes.is_some_and(|max| bytes + size > max) {
+            return Err(FileCacheError::QuotaExceeded(QuotaKind::LiveBytes));
+        }
+        Ok(())
+    }
+
+    // Counts `size` towards today's uploads if it fits
+    pub(super) async fn reserve_daily_quota(&self, owner: &str, size: u64) -> Result<(), FileCacheError> {
+        let limits = self.cache_settings.quotas.limits(owner);
+        let mut daily = self.daily_usage.lock().await;
+        if limits.max_daily_bytes.is_some_and(|max| daily.get(owner) + size > max) {
+            return Err(FileCacheError::QuotaExceeded(QuotaKind::DailyBytes));
+        }
+        daily.add(owner, size);
+        Ok(())
+    }
+
+    // Gives back a reservation of an upload that didn't go through
+    pub(super) async fn release_daily_quota(&self, owner: &str, size: u64) {
+        self.daily_usage.lock().await.sub(owner, size);
+    }
+
+    pub async fn usage(&self, owner: &str) -> Usage {
+        let (live_bytes, live_files) = {
+            let lock = self.cache.read().await;
+            Self::live_usage(&lock, owner)
+        };
+        let daily_bytes = self.daily_usage.lock().await.get(owner);
+        let limits = self.cache_settings.quotas.limits(owner);
+        Usage {
+            owner: owner.to_string(),
+            live_bytes,
+            live_files,
+            daily_bytes,
+            max_live_bytes: limits.max_live_bytes,
+            max_live_files: limits.max_live_files,
+            max_daily_bytes: limits.max_daily_bytes,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use crate::flush_entry;
+
+use super::{
+    consistency::QuarantinedFile,
+    core::{FileCache, FileCacheError},
+    supervisor::{Background, TaskRegistry},
+};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::select;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep};
+use tokio_util::sync::CancellationToken;
+
+const READ_SIZE: usize = 64 * 1024;
+// How often the scrubber looks for blobs that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Default)]
+pub struct ScrubStatus {
+    running: AtomicBool,
+    files_checked: AtomicU64,
+    bytes_checked: AtomicU64,
+    corrupt_found: AtomicU64,
+    last_checked: Mutex<Option<DateTime<Utc>>>,
+}
+
+#[derive(Serialize)]
+pub struct ScrubReport {
+    pub enabled: bool,
+    // Bytes per second
+    pub rate: Option<u64>,
+    pub running: bool,
+    // Counted since startup
+    pub files_checked: u64,
+    pub bytes_checked: u64,
+    pub corrupt_found: u64,
+    pub last_checked: Option<DateTime<Utc>>,
+    pub quarantined: Vec<QuarantinedFile>,
+}
+
+// The counters alone, as shown on /api/status
+#[derive(Serialize)]
+pub struct ScrubSummary {
+    pub corrupt_found: u64,
+    pub bytes_checked: u64,
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl ScrubStatus {
+    pub async fn summary(&self) -> ScrubSummary {
+        ScrubSummary {
+            corrupt_found: self.corrupt_found(),
+            bytes_checked: self.bytes_checked(),
+            last_run: *self.last_checked.lock().await,
+        }
+    }
+
+    pub fn files_checked(&self) -> u64 {
+        self.files_checked.load(Ordering::Relaxed)
+    }
+
+    pub fn corrupt_found(&self) -> u64 {
+        self.corrupt_found.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_checked(&self) -> u64 {
+        self.bytes_checked.load(Ordering::Relaxed)
+    }
+}
+
+enum Verdict {
+    Intact,
+    Corrupt(String),
+    // Deleted while we weren't looking, the consistency checker deals with the rest
+    Gone,
+}
+
+// Hashes a blob no faster than `rate` bytes per second, `None` when shut down halfway through
+async fn hash_blob(path: &PathBuf, rate: u64, status: &ScrubStatus, shutdown: &CancellationToken) -> std::io::Result<Option<String>> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        status.bytes_checked.fetch_add(read as u64, Ordering::Relaxed);
+        // A large blob at a low rate would otherwise hold up the shutdown for minutes
+        select! {
+            _ = shutdown.cancelled() => return Ok(None),
+            _ = sleep(Duration::from_secs_f64(read as f64 / rate as f64)) => {}
+        }
+    }
+    Ok(Some(hex::encode(hasher.finalize())))
+}
+
+/// Integrity scrubber
+impl FileCache {
+    /// Re-hashes every blob once per scrub interval in the background, corrupted ones get quarantined
+    pub(super) fn spawn_scrubber(registry: &TaskRegistry, background: &Background, status: Arc<ScrubStatus>) -> Option<JoinHandle<()>> {
+        // Off unless a rate is configured
+        let rate = background.settings.scrub_rate?;
+        let interval = background.settings.scrub_interval;
+        Some(Self::supervise(registry, "scrubber", background, move |background, health| {
+            let status = status.clone();
+            async move {
+                let Background { cache, cache_mem, library, pool, shutdown, .. } = background;
+                info!("Starting the integrity scrubber at {} bytes/s", rate);
+                while !shutdown.is_cancelled() {
+                    let cutoff = Utc::now() - chrono::Duration::from_std(interval).unwrap_or_default();
+                    // Never scrubbed first, then the longest ago
+                    let due: Result<Vec<(String, String)>, _> = sqlx::query_as(
+                        r#"
+                        SELECT uuid, blob_sha256
+                        FROM cache
+                        WHERE blob_sha256 IS NOT NULL AND quarantined_utc IS NULL AND (scrubbed_utc IS NULL OR scrubbed_utc < ?)
+                        ORDER BY scrubbed_utc ASC
+                        LIMIT ?
+                        "#,
+                    )
+                    .bind(cutoff)
+                    .bind(BATCH_SIZE)
+                    .fetch_all(&pool)
+                    .await;
+
+                    let due = match due {
+                        Ok(due) => due,
+                        Err(e) => {
+                            error!("Scrubber failed to read the database: {}", e);
+                            Vec::new()
+                        }
+                    };
+                    status.running.store(!due.is_empty(), Ordering::Relaxed);
+                    // Rows that won't come up again until the next interval
+                    let mut progress = 0;
+                    for (uuid, expected) in due {
+                        if shutdown.is_cancelled() {
+                            break;
+                        }
+                        let verdict = match hash_blob(&library.join(&uuid), rate, &status, &shutdown).await {
+                            // Neither checked nor scrubbed, it comes up again after the restart
+                            Ok(None) => break,
+                            Ok(Some(actual)) if actual == expected => Verdict::Intact,
+                            Ok(Some(actual)) => Verdict::Corrupt(format!("checksum mismatch, expected {} got {}", expected, actual)),
+                            Err(e) if e.kind() == ErrorKind::NotFound => Verdict::Gone,
+                            Err(e) => Verdict::Corrupt(format!("unreadable: {}", e)),
+                        };
+                        status.files_checked.fetch_add(1, Ordering::Relaxed);
+
+                        let result = match verdict {
+                            Verdict::Intact => {
+                                debug!("Scrubbed {}", uuid);
+                                Self::mark_scrubbed(&pool, &uuid).await
+                            }
+                            Verdict::Corrupt(reason) => {
+                                status.corrupt_found.fetch_add(1, Ordering::Relaxed);
+                                error!("Blob of {} is corrupt: {}", uuid, reason);
+                                if let Some(mut entry) = cache.write().await.remove(&uuid) {
+                                    flush_entry!(entry, &uuid, Duration::ZERO, cache_mem);
+                                }
+                                Self::quarantine_row(&pool, &uuid, &reason).await
+                            }
+                            // Skipped until the next interval, by then the consistency checker has dealt with the row
+                            Verdict::Gone => {
+                                warn!("Blob of {} disappeared before it could be scrubbed", uuid);
+                                Self::mark_scrubbed(&pool, &uuid).await
+                            }
+                        };
+                        match result {
+                            Ok(()) => progress += 1,
+                            Err(e) => error!("Failed to save the scrub result of {}: {}", uuid, e),
+                        }
+                        *status.last_checked.lock().await = Some(Utc::now());
+                    }
+                    health.beat();
+
+                    // Nothing due, or nothing could be saved and the same rows would come right back
+                    if progress == 0 {
+                        status.running.store(false, Ordering::Relaxed);
+                        select! {
+                            _ = shutdown.cancelled() => {}
+                            _ = sleep(POLL_INTERVAL) => {}
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    async fn mark_scrubbed(pool: &sqlx::Pool<sqlx::Sqlite>, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE cache SET scrubbed_utc = ? WHERE uuid = ?").bind(Utc::now()).bind(uuid).execute(pool).await?;
+        Ok(())
+    }
+
+    // Counters alone, without the database lookup of `scrub_report`
+    pub fn scrub_status(&self) -> &ScrubStatus {
+        &self.scrub
+    }
+
+    pub async fn scrub_report(&self) -> Result<ScrubReport, FileCacheError> {
+        Ok(ScrubReport {
+            enabled: self.cache_settings.scrub_rate.is_some(),
+            rate: self.cache_settings.scrub_rate,
+            running: self.scrub.running.load(Ordering::Relaxed),
+            files_checked: self.scrub.files_checked.load(Ordering::Relaxed),
+            bytes_checked: self.scrub.bytes_checked(),
+            corrupt_found: self.scrub.corrupt_found(),
+            last_checked: *self.scrub.last_checked.lock().await,
+            quarantined: self.list_quarantine().await?,
+        })
+    }
+}
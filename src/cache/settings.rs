@@ -1,3 +1,9 @@
+use super::{
+    consistency::RepairPolicy,
+    crypto::KeyRing,
+    expiry::{RetentionCurve, TtlPolicies},
+    quota::Quotas,
+};
 use std::time::Duration;
 
 #[derive(Clone)]
@@ -9,6 +15,32 @@ pub struct CacheSettings {
     pub database_path: String,
     pub max_item_size: usize,
     pub max_cache_memory: usize,
+    // Loaded separately as reading the keys can fail
+    pub keyring: Option<KeyRing>,
+    pub encrypt_uploads: bool,
+    pub quotas: Quotas,
+    pub max_library_size: Option<u64>,
+    pub min_free_space: Option<u64>,
+    pub disk_monitor_interval: Duration,
+    pub evict_to_make_room: bool,
+    pub cleanup_dry_run: bool,
+    pub rebuild_empty_index: bool,
+    pub ttl_policies: TtlPolicies,
+    pub retention: Option<RetentionCurve>,
+    pub sliding_window: Option<Duration>,
+    pub sliding_hard_cap: Duration,
+    // Deleted files are trashed instead of dropped when set
+    pub trash_grace: Option<Duration>,
+    pub trash_sweep_interval: Duration,
+    pub repair_policy: RepairPolicy,
+    pub consistency_interval: Duration,
+    // Bytes per second, scrubbing is off without it
+    pub scrub_rate: Option<u64>,
+    pub scrub_interval: Duration,
+    pub db_busy_timeout: Duration,
+    pub db_maintenance_interval: Duration,
+    pub batch_size: usize,
+    pub batch_latency: Duration,
 }
 
 impl Default for CacheSettings {
@@ -21,6 +53,29 @@ impl Default for CacheSettings {
             database_path: ":memory:".to_string(),
             max_item_size: 200_000_000,
             max_cache_memory: 200_000_000_000,
+            keyring: None,
+            encrypt_uploads: false,
+            quotas: Quotas::default(),
+            max_library_size: None,
+            min_free_space: None,
+            disk_monitor_interval: Duration::from_secs(30),
+            evict_to_make_room: false,
+            cleanup_dry_run: false,
+            rebuild_empty_index: false,
+            ttl_policies: TtlPolicies::default(),
+            retention: None,
+            sliding_window: None,
+            sliding_hard_cap: Duration::from_secs(7_776_000),
+            trash_grace: None,
+            trash_sweep_interval: Duration::from_secs(300),
+            repair_policy: RepairPolicy::default(),
+            consistency_interval: Duration::from_secs(21_600),
+            scrub_rate: None,
+            scrub_interval: Duration::from_secs(604_800),
+            db_busy_timeout: Duration::from_secs(5),
+            db_maintenance_interval: Duration::from_secs(86_400),
+            batch_size: 256,
+            batch_latency: Duration::from_millis(10),
         }
     }
 }
@@ -35,6 +90,29 @@ impl From<&crate::settings::CacheConfig> for CacheSettings {
             database_path: conf.database_path.clone(),
             max_item_size: conf.max_item_size,
             max_cache_memory: conf.max_cache_memory,
+            keyring: None,
+            encrypt_uploads: conf.encryption.enabled,
+            quotas: Quotas::from(&conf.quota),
+            max_library_size: conf.storage.max_library_size,
+            min_free_space: conf.storage.min_free_space,
+            disk_monitor_interval: Duration::from_secs(conf.storage.monitor_interval as u64),
+            evict_to_make_room: conf.storage.evict_to_make_room,
+            cleanup_dry_run: conf.storage.cleanup_dry_run,
+            rebuild_empty_index: conf.storage.rebuild_empty_index,
+            ttl_policies: TtlPolicies::from(&conf.ttl),
+            retention: RetentionCurve::new(&conf.retention, conf.max_item_size as u64),
+            sliding_window: conf.sliding.window.map(Duration::from_secs),
+            sliding_hard_cap: Duration::from_secs(conf.sliding.hard_cap),
+            trash_grace: conf.trash.grace_period.map(Duration::from_secs),
+            trash_sweep_interval: Duration::from_secs(conf.trash.sweep_interval as u64),
+            repair_policy: conf.consistency.policy,
+            consistency_interval: Duration::from_secs(conf.consistency.interval as u64),
+            scrub_rate: conf.scrub.rate.filter(|rate| *rate > 0),
+            scrub_interval: Duration::from_secs(conf.scrub.interval as u64),
+            db_busy_timeout: Duration::from_millis(conf.database.busy_timeout),
+            db_maintenance_interval: Duration::from_secs(conf.database.maintenance_interval as u64),
+            batch_size: conf.queue.batch_size.max(1),
+            batch_latency: Duration::from_millis(conf.queue.batch_latency),
         }
     }
 }
@@ -0,0 +1,243 @@
+use super::{
+    core::{FileCache, FileCacheError},
+    datetime_to_instant,
+    entry::CacheEntry,
+    instant_to_datetime,
+    library::is_blob_name,
+    settings::CacheSettings,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+use tokio::fs::{read, read_dir, remove_file, rename, try_exists, write};
+use tokio::time::Instant;
+
+const SIDECAR_VERSION: u32 = 1;
+const SIDECAR_EXTENSION: &str = ".json";
+
+// `<uuid>.json` next to the blob it describes
+pub(super) fn sidecar_of(name: &str) -> Option<&str> {
+    name.strip_suffix(SIDECAR_EXTENSION).filter(|uuid| is_blob_name(uuid))
+}
+
+fn sidecar_path(library: &Path, uuid: &str) -> PathBuf {
+    library.join(format!("{}{}", uuid, SIDECAR_EXTENSION))
+}
+
+// Everything the database knows about a blob, enough to rebuild its row
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    version: u32,
+    filename: String,
+    // Downloads don't rewrite the sidecar, a slid expiry or the read count may lag behind
+    expires: DateTime<Utc>,
+    burn_after_read: bool,
+    read_count: i64,
+    private: bool,
+    password_hash: Option<String>,
+    // Sealed with the master key like in the database
+    data_key: Option<String>,
+    e2e: bool,
+    metadata: Option<String>,
+    owner: Option<String>,
+    size: u64,
+    created: Option<DateTime<Utc>>,
+    deletion_token: Option<String>,
+    max_downloads: Option<i64>,
+    renew_until: Option<DateTime<Utc>>,
+    available_from: Option<DateTime<Utc>>,
+    sha256: Option<String>,
+}
+
+impl From<&CacheEntry> for Sidecar {
+    fn from(entry: &CacheEntry) -> Self {
+        Self {
+            version: SIDECAR_VERSION,
+            filename: entry.upload_name.clone(),
+            expires: instant_to_datetime(&entry.expiration),
+            burn_after_read: entry.burn_after_read,
+            read_count: entry.read_count,
+            private: entry.private,
+            password_hash: entry.password_hash.clone(),
+            data_key: entry.wrapped_key.clone(),
+            e2e: entry.e2e,
+            metadata: entry.metadata.clone(),
+            owner: entry.owner.clone(),
+            size: entry.size,
+            created: entry.created,
+            deletion_token: entry.deletion_token.clone(),
+            max_downloads: entry.max_downloads,
+            renew_until: entry.renew_until.as_ref().map(instant_to_datetime),
+            available_from: entry.available_from,
+            sha256: entry.checksum.clone(),
+        }
+    }
+}
+
+impl From<Sidecar> for CacheEntry {
+    fn from(sidecar: Sidecar) -> Self {
+        Self {
+            upload_name: sidecar.filename,
+            accessed: Instant::now(),
+            data: None,
+            len: (sidecar.size / 1000).max(1) as i64,
+            burn_after_read: sidecar.burn_after_read,
+            read_count: sidecar.read_count,
+            expiration: datetime_to_instant(&sidecar.expires),
+            private: sidecar.private,
+            password_hash: sidecar.password_hash,
+            wrapped_key: sidecar.data_key,
+            e2e: sidecar.e2e,
+            metadata: sidecar.metadata,
+            owner: sidecar.owner,
+            size: sidecar.size,
+            created: sidecar.created,
+            deletion_token: sidecar.deletion_token,
+            max_downloads: sidecar.max_downloads,
+            renew_until: sidecar.renew_until.as_ref().map(datetime_to_instant),
+            available_from: sidecar.available_from,
+            checksum: sidecar.sha256,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RebuildSummary {
+    pub restored: usize,
+    // Already had a row
+    pub indexed: usize,
+    pub expired: usize,
+    pub unreadable: usize,
+    // Blobs nothing can be recovered for
+    pub without_sidecar: usize,
+}
+
+impl fmt::Display for RebuildSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "restored {} entries, {} were already indexed, {} had expired, {} had unreadable sidecars and {} had none",
+            self.restored, self.indexed, self.expired, self.unreadable, self.without_sidecar
+        )
+    }
+}
+
+/// Sidecar metadata
+impl FileCache {
+    /// Written next to the blob through a temporary file so a crash never leaves half a sidecar
+    pub(super) async fn write_sidecar(library: &Path, uuid: &str, entry: &CacheEntry) -> io::Result<()> {
+        let contents = serde_json::to_vec_pretty(&Sidecar::from(entry)).map_err(io::Error::other)?;
+        let path = sidecar_path(library, uuid);
+        let temporary = path.with_extension("json.tmp");
+        write(&temporary, contents).await?;
+        rename(&temporary, &path).await
+    }
+
+    pub(super) async fn remove_sidecar(library: &Path, uuid: &str) -> io::Result<()> {
+        match remove_file(sidecar_path(library, uuid)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Brings the sidecar of a live entry up to date with its in memory state
+    pub(super) async fn save_sidecar(&self, uuid: &str) {
+        let Some(entry) = self.cache.read().await.get(uuid).cloned() else {
+            return;
+        };
+        if let Err(e) = Self::write_sidecar(&self.library, uuid, &entry).await {
+            error!("Failed to write the sidecar of {}: {}", uuid, e);
+        }
+    }
+
+    /// Whether an unindexed blob can still be brought back by `rebuild-index`. An unreadable sidecar gets the benefit of the doubt
+    pub(super) async fn is_restorable(library: &Path, uuid: &str) -> bool {
+        match read(sidecar_path(library, uuid)).await {
+            Ok(contents) => serde_json::from_slice::<Sidecar>(&contents).map_or(true, |sidecar| !CacheEntry::from(sidecar).is_expired()),
+            Err(e) => e.kind() != io::ErrorKind::NotFound,
+        }
+    }
+
+    /// Moves the sidecar next to a blob that went somewhere else
+    pub(super) async fn move_sidecar(library: &Path, uuid: &str, destination: &Path) -> io::Result<()> {
+        match rename(sidecar_path(library, uuid), sidecar_path(destination, uuid)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of blobs in the library, used to tell a lost database from an empty library
+    pub(super) async fn count_blobs(library: &Path) -> io::Result<usize> {
+        let mut blobs = 0;
+        let mut paths = read_dir(library).await?;
+        while let Some(file) = paths.next_entry().await? {
+            if file.file_name().to_str().is_some_and(is_blob_name) {
+                blobs += 1;
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Inserts a row for every sidecar whose blob is present and whose row is missing
+    pub(super) async fn restore_sidecars(library: &Path, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<RebuildSummary, FileCacheError> {
+        let mut summary = RebuildSummary::default();
+        let mut paths = read_dir(library).await.map_err(FileCacheError::IoError)?;
+        while let Some(file) = paths.next_entry().await.map_err(FileCacheError::IoError)? {
+            let Some(filename) = file.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !is_blob_name(&filename) {
+                continue;
+            }
+            let uuid = filename;
+
+            let indexed: Option<(String,)> = sqlx::query_as("SELECT uuid FROM cache WHERE uuid = ?").bind(&uuid).fetch_optional(pool).await.map_err(FileCacheError::DbError)?;
+            if indexed.is_some() {
+                summary.indexed += 1;
+                continue;
+            }
+
+            let path = sidecar_path(library, &uuid);
+            if !try_exists(&path).await.unwrap_or(false) {
+                warn!("{} has no sidecar, it can't be restored", uuid);
+                summary.without_sidecar += 1;
+                continue;
+            }
+            let sidecar: Sidecar = match read(&path).await.map_err(|e| e.to_string()).and_then(|contents| serde_json::from_slice(&contents).map_err(|e| e.to_string())) {
+                Ok(sidecar) => sidecar,
+                Err(e) => {
+                    error!("Unreadable sidecar for {}: {}", uuid, e);
+                    summary.unreadable += 1;
+                    continue;
+                }
+            };
+            if sidecar.version > SIDECAR_VERSION {
+                error!("Sidecar for {} was written by a newer version", uuid);
+                summary.unreadable += 1;
+                continue;
+            }
+
+            let entry = CacheEntry::from(sidecar);
+            if entry.is_expired() {
+                // Left for the orphan cleanup
+                debug!("{} expired while it wasn't indexed", uuid);
+                summary.expired += 1;
+                continue;
+            }
+            Self::push_to_db(pool, &uuid, &entry).await.map_err(FileCacheError::DbError)?;
+            debug!("Restored {} from its sidecar", uuid);
+            summary.restored += 1;
+        }
+        Ok(summary)
+    }
+
+    /// Reconstructs the database from the sidecars in the library
+    pub async fn rebuild_index(cache_settings: &CacheSettings, library_path: &str) -> Result<RebuildSummary, FileCacheError> {
+        let library: PathBuf = library_path.into();
+        let pool = Self::connect_database(cache_settings).await.map_err(FileCacheError::DbError)?;
+        Self::init_schema(&pool).await.map_err(FileCacheError::DbError)?;
+        Self::restore_sidecars(&library, &pool).await
+    }
+}
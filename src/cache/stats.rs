@@ -0,0 +1,60 @@
+use super::{core::FileCache, io::FileContent};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counted since startup, exported by the metrics endpoint
+#[derive(Default)]
+pub struct CacheCounters {
+    hits_in_memory: AtomicU64,
+    hits_on_disk: AtomicU64,
+    misses_in_memory: AtomicU64,
+    misses_on_disk: AtomicU64,
+    expired: AtomicU64,
+    burned: AtomicU64,
+}
+
+pub struct CacheMetrics {
+    pub hits_in_memory: u64,
+    pub hits_on_disk: u64,
+    // Loaded into memory on the way out
+    pub misses_in_memory: u64,
+    // Streamed from the library, there was no memory to spare
+    pub misses_on_disk: u64,
+    pub expired: u64,
+    pub burned: u64,
+    pub live_entries: usize,
+}
+
+impl CacheCounters {
+    pub(super) fn record_read(&self, content: &FileContent, hit: bool) {
+        let counter = match (content, hit) {
+            (FileContent::InMemory(_), true) => &self.hits_in_memory,
+            (FileContent::OnDisk(_), true) => &self.hits_on_disk,
+            (FileContent::InMemory(_), false) => &self.misses_in_memory,
+            (FileContent::OnDisk(_), false) => &self.misses_on_disk,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_expired(&self, count: usize) {
+        self.expired.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_burned(&self) {
+        self.burned.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters
+impl FileCache {
+    pub async fn cache_metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits_in_memory: self.counters.hits_in_memory.load(Ordering::Relaxed),
+            hits_on_disk: self.counters.hits_on_disk.load(Ordering::Relaxed),
+            misses_in_memory: self.counters.misses_in_memory.load(Ordering::Relaxed),
+            misses_on_disk: self.counters.misses_on_disk.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+            burned: self.counters.burned.load(Ordering::Relaxed),
+            live_entries: self.cache.read().await.len(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cache::mem::CacheMemory;
+
+use super::{core::FileCache, entry::CacheEntry, settings::CacheSettings, stats::CacheCounters};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::RwLock;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{Duration, Instant, interval, interval_at, sleep};
+use tokio_util::sync::CancellationToken;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A task that stayed up this long starts over at the minimum backoff when it fails again
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
+// Everything the background routines share with the cache, handed to every (re)start of a task
+#[derive(Clone)]
+pub(super) struct Background {
+    pub(super) cache: Arc<RwLock<HashMap<String, CacheEntry>>>,
+    pub(super) cache_mem: Arc<RwLock<CacheMemory>>,
+    pub(super) library: PathBuf,
+    pub(super) pool: sqlx::Pool<sqlx::Sqlite>,
+    pub(super) settings: Arc<CacheSettings>,
+    pub(super) counters: Arc<CacheCounters>,
+    pub(super) shutdown: CancellationToken,
+}
+
+#[derive(Default)]
+pub struct TaskHealth {
+    running: AtomicBool,
+    restarts: AtomicU64,
+    // Unix milliseconds, 0 until the first run
+    last_run: AtomicI64,
+    last_failure: AtomicI64,
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Serialize)]
+pub struct TaskReport {
+    pub name: &'static str,
+    pub running: bool,
+    pub restarts: u64,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    // Every routine is up, none of them is waiting out a backoff
+    pub healthy: bool,
+    pub tasks: Vec<TaskReport>,
+}
+
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: Mutex<Vec<(&'static str, Arc<TaskHealth>)>>,
+}
+
+fn from_millis(millis: i64) -> Option<DateTime<Utc>> {
+    Some(millis).filter(|millis| *millis > 0).and_then(DateTime::from_timestamp_millis)
+}
+
+fn panic_message(error: JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let payload = error.into_panic();
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "panicked".to_string()),
+    }
+}
+
+impl TaskHealth {
+    /// Marks a finished unit of work
+    pub(super) fn beat(&self) {
+        self.last_run.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    fn fail(&self, error: String) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+        self.last_failure.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = Some(error);
+        }
+    }
+}
+
+impl TaskRegistry {
+    fn register(&self, name: &'static str) -> Arc<TaskHealth> {
+        let health = Arc::new(TaskHealth::default());
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push((name, health.clone()));
+        }
+        health
+    }
+
+    pub fn report(&self) -> Vec<TaskReport> {
+        let Ok(tasks) = self.tasks.lock() else {
+            return Vec::new();
+        };
+        tasks
+            .iter()
+            .map(|(name, health)| TaskReport {
+                name,
+                running: health.running.load(Ordering::Relaxed),
+                restarts: health.restarts.load(Ordering::Relaxed),
+                last_run: from_millis(health.last_run.load(Ordering::Relaxed)),
+                last_failure: from_millis(health.last_failure.load(Ordering::Relaxed)),
+                last_error: health.last_error.lock().ok().and_then(|error| error.clone()),
+            })
+            .collect()
+    }
+}
+
+/// Supervision of the background routines
+impl FileCache {
+    /// Runs `task` until it returns, restarting it with backoff whenever it panics. Tasks only return once shutting down
+    pub(super) fn supervise<F, Fut>(registry: &TaskRegistry, name: &'static str, background: &Background, task: F) -> JoinHandle<()>
+    where
+        F: Fn(Background, Arc<TaskHealth>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let health = registry.register(name);
+        let background = background.clone();
+        tokio::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                health.running.store(true, Ordering::Relaxed);
+                let started = Instant::now();
+                let result = tokio::spawn(task(background.clone(), health.clone())).await;
+                health.running.store(false, Ordering::Relaxed);
+                let Err(e) = result else {
+                    break;
+                };
+
+                let error = panic_message(e);
+                health.fail(error.clone());
+                // It already had its chance to wind down
+                if background.shutdown.is_cancelled() {
+                    error!("Background task {} failed while shutting down: {}", name, error);
+                    break;
+                }
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = MIN_BACKOFF;
+                }
+                error!("Background task {} failed: {}, restarting in {:?}", name, error, backoff);
+                // A shutdown during the backoff restarts it right away so it can wind down
+                select! {
+                    _ = background.shutdown.cancelled() => {}
+                    _ = sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                info!("Restarting background task {}", name);
+            }
+        })
+    }
+
+    /// Supervised task running `job` every `period`, right away unless `delayed`
+    pub(super) fn supervise_periodic<F, Fut>(registry: &TaskRegistry, name: &'static str, background: &Background, period: Duration, delayed: bool, job: F) -> JoinHandle<()>
+    where
+        F: Fn(Background) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::supervise(registry, name, background, move |background, health| {
+            let job = job.clone();
+            async move {
+                let mut ticker = match delayed {
+                    true => interval_at(Instant::now() + period, period),
+                    false => interval(period),
+                };
+                loop {
+                    select! {
+                        biased;
+                        _ = background.shutdown.cancelled() => break,
+                        _ = ticker.tick() => {
+                            job(background.clone()).await;
+                            health.beat();
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    pub fn task_health(&self) -> HealthReport {
+        let tasks = self.supervisor.report();
+        HealthReport {
+            healthy: tasks.iter().all(|task| task.running),
+            tasks,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use super::{
+    core::{FileCache, FileCacheError},
+    entry::{CacheEntryRow, ENTRY_COLUMNS},
+    instant_to_datetime,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
+use sqlx::{FromRow, SqliteExecutor};
+use std::io;
+use std::path::Path;
+use tokio::time::{Duration, Instant};
+
+#[derive(FromRow)]
+struct TrashedRow {
+    uuid: String,
+    filename: String,
+    size: i64,
+    owner: Option<String>,
+    e2e: i8,
+    deleted_utc: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct TrashedFile {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub owner: Option<String>,
+    pub deleted: DateTime<Utc>,
+    // When the sweep purges it for good
+    pub purge_at: DateTime<Utc>,
+}
+
+/// Trash
+impl FileCache {
+    /// Moves an entry out of the way, or drops it when there's no grace period
+    pub(super) async fn discard(uuid: &str, library: &Path, pool: impl SqliteExecutor<'_>, grace: Option<Duration>) -> Result<(), FileCacheError> {
+        Self::discard_row(pool, uuid, grace).await.map_err(FileCacheError::DbError)?;
+        Self::discard_files(uuid, library, grace).await.map_err(FileCacheError::IoError)
+    }
+
+    /// Database half of `discard`
+    pub(super) async fn discard_row(pool: impl SqliteExecutor<'_>, uuid: &str, grace: Option<Duration>) -> Result<(), sqlx::Error> {
+        if grace.is_none() {
+            return Self::delete_from_db(pool, uuid).await;
+        }
+        debug!("Moving {} to the trash", uuid);
+        sqlx::query("UPDATE cache SET deleted_utc = ? WHERE uuid = ?").bind(Utc::now()).bind(uuid).execute(pool).await?;
+        Ok(())
+    }
+
+    /// Library half of `discard`, run once the row is gone
+    pub(super) async fn discard_files(uuid: &str, library: &Path, grace: Option<Duration>) -> io::Result<()> {
+        if grace.is_none() {
+            match Self::delete_file(library, uuid).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        // Deleted files shouldn't come back with a rebuilt database
+        Self::remove_sidecar(library, uuid).await
+    }
+
+    /// Purges everything that has been in the trash longer than `grace`, returns how many were purged
+    pub(super) async fn sweep_trash(library: &Path, pool: &sqlx::Pool<sqlx::Sqlite>, grace: Duration) -> Result<usize, FileCacheError> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(grace).unwrap_or_default();
+        let expired: Vec<(String,)> = sqlx::query_as("SELECT uuid FROM cache WHERE deleted_utc IS NOT NULL AND deleted_utc < ?")
+            .bind(cutoff)
+            .fetch_all(pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+
+        for (uuid,) in &expired {
+            info!("Purging {} from the trash", uuid);
+            if let Err(e) = Self::drop_item(uuid, library, pool).await {
+                warn!("Error dropping file: {:#?}", e)
+            }
+        }
+        Ok(expired.len())
+    }
+
+    pub async fn list_trash(&self) -> Result<Vec<TrashedFile>, FileCacheError> {
+        let rows: Vec<TrashedRow> = sqlx::query_as(
+            r#"
+            SELECT uuid, filename, COALESCE(size_bytes, file_size * 1000) AS size, owner, e2e, deleted_utc
+            FROM cache
+            WHERE deleted_utc IS NOT NULL
+            ORDER BY deleted_utc DESC
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(FileCacheError::DbError)?;
+
+        let grace = chrono::Duration::from_std(self.cache_settings.trash_grace.unwrap_or_default()).unwrap_or_default();
+        Ok(rows
+            .into_iter()
+            .map(|row| TrashedFile {
+                id: row.uuid,
+                // Same masking as `CacheEntry::masked`
+                filename: if row.e2e == 1 { "<end-to-end encrypted>".to_string() } else { row.filename },
+                size: row.size.max(0) as u64,
+                owner: row.owner,
+                deleted: row.deleted_utc,
+                purge_at: row.deleted_utc + grace,
+            })
+            .collect())
+    }
+
+    /// Brings a trashed entry back, expired ones get `ttl` (or the default lifetime) and burned ones their reads back
+    pub async fn restore_file(&self, uuid: &str, ttl: Option<Duration>) -> Result<(), FileCacheError> {
+        let row: Option<CacheEntryRow> = sqlx::query_as(&format!("SELECT {} FROM cache WHERE uuid = ? AND deleted_utc IS NOT NULL", ENTRY_COLUMNS))
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+        let Some(row) = row else {
+            return Err(FileCacheError::NotFound);
+        };
+
+        let (uuid, mut entry) = row.into();
+        let ttl = match ttl {
+            Some(ttl) => Some(ttl),
+            None if entry.expiration <= Instant::now() => Some(self.cache_settings.on_disk_ttl),
+            None => None,
+        };
+        if let Some(ttl) = ttl {
+            entry.expiration = Instant::now().checked_add(ttl).ok_or(FileCacheError::InvalidOptions("expiry is too far away"))?;
+        }
+        // Whatever is still expired was burned or ran out of downloads
+        if entry.is_expired() {
+            entry.read_count = 0;
+        }
+
+        sqlx::query("UPDATE cache SET deleted_utc = NULL, read_count = ?, expiration_utc = ? WHERE uuid = ?")
+            .bind(entry.read_count)
+            .bind(instant_to_datetime(&entry.expiration))
+            .bind(&uuid)
+            .execute(&self.pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+        info!("Restored {} from the trash", uuid);
+        if let Err(e) = Self::write_sidecar(&self.library, &uuid, &entry).await {
+            warn!("Failed to write the sidecar of {}: {}", uuid, e);
+        }
+        self.cache.write().await.insert(uuid, entry);
+        Ok(())
+    }
+
+    /// Purges a trashed entry right away
+    pub async fn purge_file(&self, uuid: &str) -> Result<(), FileCacheError> {
+        let trashed: Option<(String,)> = sqlx::query_as("SELECT uuid FROM cache WHERE uuid = ? AND deleted_utc IS NOT NULL")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(FileCacheError::DbError)?;
+        if trashed.is_none() {
+            return Err(FileCacheError::NotFound);
+        }
+        info!("Purging {} from the trash", uuid);
+        Self::drop_item(uuid, &self.library, &self.pool).await
+    }
+
+    pub(super) async fn trash_bytes(&self) -> u64 {
+        if self.cache_settings.trash_grace.is_none() {
+            return 0;
+        }
+        let bytes: Result<(i64,), _> = sqlx::query_as("SELECT COALESCE(SUM(COALESCE(size_bytes, file_size * 1000)), 0) FROM cache WHERE deleted_utc IS NOT NULL").fetch_one(&self.pool).await;
+        bytes.map(|(bytes,)| bytes.max(0) as u64).unwrap_or(0)
+    }
+
+    /// Purges the oldest trash first until `needed` bytes are freed, returns what was freed
+    pub(super) async fn purge_for_space(&self, needed: u64) -> u64 {
+        let rows: Vec<(String, i64)> = match sqlx::query_as("SELECT uuid, COALESCE(size_bytes, file_size * 1000) FROM cache WHERE deleted_utc IS NOT NULL ORDER BY deleted_utc ASC")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("Failed to read the trash: {}", e);
+                return 0;
+            }
+        };
+
+        let mut freed = 0;
+        for (uuid, size) in rows {
+            if freed >= needed {
+                break;
+            }
+            info!("Purging {} from the trash to make room", uuid);
+            match Self::drop_item(&uuid, &self.library, &self.pool).await {
+                Ok(()) => freed += size.max(0) as u64,
+                Err(e) => warn!("Error dropping file: {:#?}", e),
+            }
+        }
+        freed
+    }
+}
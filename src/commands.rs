@@ -0,0 +1,92 @@
+use crate::cache::{FileCache, settings::CacheSettings};
+use crate::settings::Configuration;
+use crate::users::{Role, UserStore};
+use log::{error, info, warn};
+use std::io::BufRead;
+
+fn usage() -> i32 {
+    error!("Usage: box-korvatunturi-org [rotate-keys | rebuild-index | add-user <username> <uploader|admin> | remove-user <username>]");
+    2
+}
+
+/// Maintenance commands which run instead of the server, returns the exit code if a command was given
+pub async fn run(args: &[String], config: &Configuration, cache_settings: &CacheSettings) -> Option<i32> {
+    let command = args.first()?;
+    if !cache_settings.is_persistent() && command != "rotate-keys" {
+        warn!("The database is in memory, changes made by {} are lost when the command exits", command);
+    }
+
+    Some(match (command.as_str(), &args[1..]) {
+        ("rotate-keys", []) => match FileCache::rotate_keys(cache_settings).await {
+            Ok(count) => {
+                info!("Re-wrapped {} data keys with the current master key", count);
+                0
+            }
+            Err(e) => {
+                error!("Error rotating keys: {:?}", e);
+                1
+            }
+        },
+        ("rebuild-index", []) => match FileCache::rebuild_index(cache_settings, &config.cache_path).await {
+            Ok(summary) => {
+                info!("Rebuilt the database: {}", summary);
+                0
+            }
+            Err(e) => {
+                error!("Error rebuilding the database: {:?}", e);
+                1
+            }
+        },
+        ("add-user", [username, role]) => {
+            let Ok(role) = role.parse::<Role>() else {
+                return Some(usage());
+            };
+            // The password is read from stdin so it doesn't end up in the shell history
+            info!("Enter the password for {}:", username);
+            let mut password = String::new();
+            if std::io::stdin().lock().read_line(&mut password).is_err() || password.trim_end_matches(['\r', '\n']).is_empty() {
+                error!("No password given");
+                return Some(1);
+            }
+            let password = password.trim_end_matches(['\r', '\n']);
+
+            match UserStore::connect(cache_settings).await {
+                Ok(store) => match store.add_user(username, password, role).await {
+                    Ok(()) => {
+                        info!("Saved user {} with role {}", username, role);
+                        0
+                    }
+                    Err(e) => {
+                        error!("Error saving user: {:?}", e);
+                        1
+                    }
+                },
+                Err(e) => {
+                    error!("Error opening database: {}", e);
+                    1
+                }
+            }
+        }
+        ("remove-user", [username]) => match UserStore::connect(cache_settings).await {
+            Ok(store) => match store.remove_user(username).await {
+                Ok(true) => {
+                    info!("Removed user {}", username);
+                    0
+                }
+                Ok(false) => {
+                    warn!("No such user {}", username);
+                    1
+                }
+                Err(e) => {
+                    error!("Error removing user: {:?}", e);
+                    1
+                }
+            },
+            Err(e) => {
+                error!("Error opening database: {}", e);
+                1
+            }
+        },
+        _ => usage(),
+    })
+}
@@ -1,3 +1,5 @@
 mod routes;
 
+pub use routes::admin::*;
+pub use routes::login::*;
 pub use routes::pages::*;
@@ -0,0 +1,164 @@
+use crate::cache::{
+    FileCache,
+    disk::DiskUsage,
+    listing::{FileFilter, FileSummary},
+    manage::MemoryUsage,
+};
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use askama::Template;
+use chrono::Utc;
+use log::{error, info};
+use tokio::time::Duration;
+
+// How far the extend action pushes expiry unless told otherwise
+const DEFAULT_EXTEND_HOURS: u64 = 24;
+// Ten years, anything more is a typo
+const MAX_EXTEND_HOURS: u64 = 87_600;
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit + 1 < UNITS.len() {
+        size /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+struct FileRow {
+    id: String,
+    filename: String,
+    size: String,
+    created: String,
+    expires: String,
+    read_count: i64,
+    burn_after_read: bool,
+    owner: String,
+    // Only set while the file is still waiting for its publication time
+    available_from: Option<String>,
+}
+
+impl From<FileSummary> for FileRow {
+    fn from(file: FileSummary) -> Self {
+        Self {
+            id: file.id,
+            filename: file.filename,
+            size: human_size(file.size),
+            created: file.created.map(|c| c.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_else(|| "-".to_string()),
+            expires: file.expires.format("%Y-%m-%d %H:%M").to_string(),
+            read_count: file.read_count,
+            burn_after_read: file.burn_after_read,
+            owner: file.owner.unwrap_or_else(|| "-".to_string()),
+            available_from: file.available_from.filter(|from| *from > Utc::now()).map(|from| from.format("%Y-%m-%d %H:%M").to_string()),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin.html.j2", ext = "html")]
+struct AdminPage<'a> {
+    server_name: &'a str,
+    files: Vec<FileRow>,
+    next_cursor: Option<String>,
+    // Current filters, kept across searches, paging and actions
+    name: &'a str,
+    owner: &'a str,
+    sort: &'a str,
+    order: &'a str,
+    query: &'a str,
+    library_size: String,
+    library_max: String,
+    free_space: String,
+    memory_used: String,
+    memory_max: String,
+    cached_files: u64,
+    extend_hours: u64,
+}
+
+// Blank search fields mean no filter
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
+}
+
+pub async fn admin(req: HttpRequest, data: web::Data<(String, String)>, cache: web::Data<FileCache>, query: web::Query<FileFilter>) -> actix_web::Result<HttpResponse> {
+    let mut filter = query.into_inner();
+    filter.name = non_empty(filter.name);
+    filter.owner = non_empty(filter.owner);
+    filter.cursor = non_empty(filter.cursor);
+
+    let page = match cache.list_files(&filter).await {
+        Ok(page) => page,
+        Err(e) => {
+            error!("Error listing files: {:?}", e);
+            return Ok(HttpResponse::BadRequest().body("Invalid filters"));
+        }
+    };
+    let DiskUsage { free_bytes, library_bytes, max_library_size, .. } = cache.disk_usage();
+    let MemoryUsage { used_bytes, max_bytes, cached_files } = cache.memory_usage().await;
+
+    let page = AdminPage {
+        server_name: &data.0,
+        files: page.files.into_iter().map(FileRow::from).collect(),
+        next_cursor: page.next_cursor,
+        name: filter.name.as_deref().unwrap_or(""),
+        owner: filter.owner.as_deref().unwrap_or(""),
+        sort: filter.sort.as_str(),
+        order: filter.order.as_str(),
+        query: req.query_string(),
+        library_size: human_size(library_bytes),
+        library_max: max_library_size.map(human_size).unwrap_or_else(|| "unlimited".to_string()),
+        free_space: human_size(free_bytes),
+        memory_used: human_size(used_bytes),
+        memory_max: human_size(max_bytes),
+        cached_files,
+        extend_hours: DEFAULT_EXTEND_HOURS,
+    };
+    Ok(match page.render() {
+        Ok(page) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page),
+        Err(e) => {
+            error!("error templating: {}", e);
+            HttpResponse::InternalServerError().body("Error templating admin page")
+        }
+    })
+}
+
+// The form has repeated `id` fields so it's read as plain pairs
+pub async fn admin_action(req: HttpRequest, cache: web::Data<FileCache>, form: web::Form<Vec<(String, String)>>) -> actix_web::Result<HttpResponse> {
+    let mut action = None;
+    let mut ids = Vec::new();
+    let mut row = None;
+    let mut extend_hours = DEFAULT_EXTEND_HOURS;
+    for (key, value) in form.into_inner() {
+        match key.as_str() {
+            "action" => action = Some(value),
+            "id" => ids.push(value),
+            "extend_hours" => extend_hours = value.parse::<u64>().unwrap_or(DEFAULT_EXTEND_HOURS).min(MAX_EXTEND_HOURS),
+            "row" => row = Some(value),
+            _ => {}
+        }
+    }
+    // Per row buttons carry both the action and the file, and win over the checkboxes
+    if let Some((row_action, id)) = row.as_deref().and_then(|row| row.split_once(':')) {
+        action = Some(row_action.to_string());
+        ids = vec![id.to_string()];
+    }
+
+    let changed = match action.as_deref() {
+        Some("delete") => cache.delete_files(&ids).await,
+        Some("extend") => cache.extend_expiry(&ids, Duration::from_secs(extend_hours * 3600)).await,
+        Some("toggle_burn") => cache.toggle_burn_after_read(&ids).await,
+        _ => return Ok(HttpResponse::BadRequest().body("Unknown action")),
+    };
+    info!("Admin action {:?} applied to {} of {} files", action, changed, ids.len());
+
+    // Back to the same page of the listing
+    let location = match req.query_string() {
+        "" => "/admin".to_string(),
+        query => format!("/admin?{}", query),
+    };
+    Ok(HttpResponse::SeeOther().insert_header((header::LOCATION, location)).finish())
+}
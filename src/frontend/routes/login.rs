@@ -0,0 +1,94 @@
+use crate::api::limiter::AttemptLimiter;
+use crate::api::middleware::{ClientIp, SESSION_COOKIE};
+use crate::users::UserStore;
+use actix_web::cookie::{Cookie, SameSite, time};
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use askama::Template;
+use log::{error, info, warn};
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "login.html.j2", ext = "html")]
+struct LoginPage<'a> {
+    server_name: &'a str,
+    // Where to go after logging in
+    next: &'a str,
+    failed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+// Only redirect within the site
+fn safe_next(next: Option<&str>) -> &str {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") => next,
+        _ => "/upload",
+    }
+}
+
+fn render_login(server_name: &str, next: &str, failed: bool) -> HttpResponse {
+    let page = LoginPage { server_name, next, failed };
+    match page.render() {
+        Ok(page) if failed => HttpResponse::Unauthorized().content_type("text/html; charset=utf-8").body(page),
+        Ok(page) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page),
+        Err(e) => {
+            error!("error templating: {}", e);
+            HttpResponse::InternalServerError().body("Error templating login page")
+        }
+    }
+}
+
+pub async fn login_page(data: web::Data<(String, String)>, query: web::Query<LoginQuery>) -> actix_web::Result<HttpResponse> {
+    Ok(render_login(&data.0, safe_next(query.next.as_deref()), false))
+}
+
+pub async fn login(req: HttpRequest, data: web::Data<(String, String)>, users: web::Data<UserStore>, limiter: web::Data<AttemptLimiter>, client_ip: web::Data<ClientIp>, form: web::Form<LoginForm>) -> actix_web::Result<HttpResponse> {
+    let form = form.into_inner();
+    let next = safe_next(form.next.as_deref());
+    let client = client_ip.resolve(&req);
+    // Login failures share the limiter with file passwords
+    let limit_key = format!("login:{}", form.username);
+
+    if limiter.is_limited(client, &limit_key) {
+        return Ok(HttpResponse::TooManyRequests().finish());
+    }
+
+    let Some(user) = users.authenticate(&form.username, &form.password).await else {
+        warn!("{:?}: failed login for {}", client, form.username);
+        limiter.record_failure(client, &limit_key);
+        return Ok(render_login(&data.0, next, true));
+    };
+    limiter.clear(client, &limit_key);
+    info!("{} logged in", user.username);
+
+    let token = users.create_session(user).await;
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(time::Duration::seconds(users.session_ttl().as_secs() as i64))
+        .finish();
+
+    Ok(HttpResponse::SeeOther().cookie(cookie).insert_header((header::LOCATION, next)).finish())
+}
+
+pub async fn logout(req: HttpRequest, users: web::Data<UserStore>) -> actix_web::Result<HttpResponse> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        users.end_session(cookie.value()).await;
+    }
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+
+    Ok(HttpResponse::SeeOther().cookie(removal).insert_header((header::LOCATION, "/")).finish())
+}
@@ -17,18 +17,41 @@ struct UploadPage<'a> {
     server_url: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "download.html.j2", ext = "html")]
+struct DownloadPage<'a> {
+    server_name: &'a str,
+    file_id: &'a str,
+}
+
 #[derive(Template)]
 #[template(path = "not_found.html.j2", ext = "html")]
 struct NotFound<'a> {
     server_name: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "not_yet_available.html.j2", ext = "html")]
+pub struct NotYetAvailable<'a> {
+    pub server_name: &'a str,
+    pub available_from: &'a str,
+}
+
 #[derive(Template)]
 #[template(path = "forbidden.html.j2", ext = "html")]
 pub struct Forbidden<'a> {
     pub server_name: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "password.html.j2", ext = "html")]
+pub struct PasswordPrompt<'a> {
+    pub server_name: &'a str,
+    // Where the form posts to, keeps the signature of private files intact
+    pub action: &'a str,
+    pub failed: bool,
+}
+
 pub async fn index(data: web::Data<(String, String)>) -> actix_web::Result<HttpResponse> {
     let page = MainPage { server_name: &data.0, source: &data.1 };
     Ok(match page.render() {
@@ -58,6 +81,19 @@ pub async fn upload(req: HttpRequest, data: web::Data<(String, String)>) -> acti
     })
 }
 
+// Landing page for end-to-end encrypted files, the key never leaves the URL fragment
+pub async fn download(data: web::Data<(String, String)>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let page = DownloadPage { server_name: &data.0, file_id: &file_id };
+    Ok(match page.render() {
+        Ok(page) => HttpResponse::Ok().body(page),
+        Err(e) => {
+            error!("error templating: {}", e);
+            HttpResponse::InternalServerError().body("Error templating download page")
+        }
+    })
+}
+
 pub async fn not_found(data: web::Data<(String, String)>) -> actix_web::Result<HttpResponse> {
     let page = NotFound { server_name: &data.0 };
     Ok(match page.render() {
@@ -1,15 +1,19 @@
 mod api;
 mod cache;
+mod commands;
 mod frontend;
 mod settings;
-use crate::cache::{FileCache, settings::CacheSettings};
+mod users;
+use crate::cache::{FileCache, crypto::KeyRing, settings::CacheSettings};
 use crate::settings::Configuration;
+use crate::users::{Role, UserStore};
 use actix_web::{App, HttpServer, middleware::Logger, web};
 use ipnet::IpNet;
 use log::{debug, warn};
 use log::{error, info};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -26,8 +30,23 @@ async fn main() -> std::io::Result<()> {
     };
     debug!("Loaded config: {:#?}", config);
 
+    let mut cache_settings = CacheSettings::from(&config.cache);
+    cache_settings.keyring = match KeyRing::load(&config.cache.encryption) {
+        Ok(k) => k,
+        Err(e) => {
+            error!("Error loading encryption keys: {}", e);
+            exit(1)
+        }
+    };
+
+    // Maintenance commands run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = commands::run(&args, &config, &cache_settings).await {
+        exit(code)
+    }
+
     // This can technically delay panic
-    let cache = match FileCache::new(CacheSettings::from(&config.cache), &config.cache_path).await {
+    let cache = match FileCache::new(cache_settings, &config.cache_path).await {
         Ok(c) => c,
         Err(e) => {
             error!("Error initializing cache: {}", e);
@@ -35,6 +54,14 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    let users = match UserStore::new(cache.pool(), Duration::from_secs(config.users.session_ttl as u64)).await {
+        Ok(u) => Arc::new(u.with_tokens(&config.api_tokens)),
+        Err(e) => {
+            error!("Error initializing user store: {}", e);
+            exit(1)
+        }
+    };
+
     let mut whitelist_list = Vec::new();
     for ip_range in config.ip_whitelist.iter() {
         if let Ok(range) = ip_range.parse::<IpNet>() {
@@ -49,28 +76,83 @@ async fn main() -> std::io::Result<()> {
     let logging_format = if let Some(h) = config.forward_header.as_ref() { format!("%{{{}}}i {}", h, base) } else { format!("%{{r}}a {}", base) };
 
     // Middlewear & shared data
-    let whitelist = api::middleware::IpWhitelist::new(whitelist_list, config.forward_header);
+    let service_info = web::Data::new(api::health::ServiceInfo::new(&config));
+    let client_ip = web::Data::new(api::middleware::ClientIp::new(config.forward_header.clone()));
+    let metrics = web::Data::new(api::metrics::Metrics::default());
+    let whitelist = api::middleware::IpWhitelist::new(whitelist_list, config.forward_header).with_denied_counter(metrics.whitelist_denied.clone());
     let server_info = Arc::new((config.service_name, config.source_code));
     let cache_data = web::Data::new(cache);
+    let shutdown_cache = cache_data.clone();
+    let signer = web::Data::new(api::signing::UrlSigner::new(&config.signing));
+    let limiter = web::Data::new(api::limiter::AttemptLimiter::new(&config.password));
+    let uploader_auth = api::middleware::UserAuth::new(users.clone(), limiter.clone().into_inner(), client_ip.clone().into_inner(), Role::Uploader);
+    let admin_auth = api::middleware::UserAuth::new(users.clone(), limiter.clone().into_inner(), client_ip.clone().into_inner(), Role::Admin);
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::new(&logging_format))
             .app_data(web::Data::from(server_info.clone()))
+            .app_data(web::Data::from(users.clone()))
+            .app_data(client_ip.clone())
+            .app_data(limiter.clone())
+            .app_data(metrics.clone())
             .default_service(web::to(frontend::not_found))
             .route("/", web::get().to(frontend::index))
             .route("/favicon.ico", web::get().to(frontend::favicon))
             .route("/index.html", web::get().to(frontend::index))
-            .service(web::resource("/upload").wrap(whitelist.clone()).route(web::get().to(frontend::upload)))
+            .route("/d/{id}", web::get().to(frontend::download))
+            .route("/login", web::get().to(frontend::login_page))
+            .route("/login", web::post().to(frontend::login))
+            .route("/logout", web::get().to(frontend::logout))
+            // Probes for load balancers and watchdogs
+            .route("/healthz", web::get().to(api::health::healthz))
+            .service(web::resource("/readyz").app_data(cache_data.clone()).route(web::get().to(api::health::readyz)))
+            .service(web::resource("/metrics").app_data(cache_data.clone()).wrap(whitelist.clone()).route(web::get().to(api::health::metrics)))
+            .service(web::resource("/upload").wrap(whitelist.clone()).wrap(uploader_auth.clone()).route(web::get().to(frontend::upload)))
+            .service(
+                web::resource("/admin")
+                    .app_data(cache_data.clone())
+                    .wrap(whitelist.clone())
+                    .wrap(admin_auth.clone())
+                    .route(web::get().to(frontend::admin))
+                    .route(web::post().to(frontend::admin_action)),
+            )
             .service(
                 web::scope("/api")
                     .app_data(cache_data.clone())
+                    .app_data(signer.clone())
+                    .service(
+                        web::resource("/admin/consistency")
+                            .wrap(whitelist.clone())
+                            .wrap(admin_auth.clone())
+                            .route(web::get().to(api::admin::consistency))
+                            .route(web::post().to(api::admin::check_consistency)),
+                    )
+                    .service(web::resource("/admin/integrity").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::admin::integrity)))
+                    .service(web::resource("/admin/queue").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::admin::queue)))
+                    .service(web::resource("/admin/health").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::admin::health)))
+                    .service(web::resource("/diagnostics").app_data(service_info.clone()).wrap(whitelist.clone()).route(web::get().to(api::health::diagnostics)))
+                    .service(web::resource("/admin/files").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::admin::files)))
+                    .service(web::resource("/admin/trash").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::admin::trash)))
+                    .service(web::resource("/admin/trash/{id}").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::delete().to(api::admin::purge)))
+                    .service(web::resource("/admin/trash/{id}/restore").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::post().to(api::admin::restore)))
                     .route("/download/{id}", web::get().to(api::public::download))
-                    .service(web::resource("/status").wrap(whitelist.clone()).route(web::get().to(api::private::status)))
-                    .service(web::resource("/upload").wrap(whitelist.clone()).route(web::post().to(api::private::upload))),
+                    .route("/download/{id}", web::post().to(api::public::download_form))
+                    .route("/metadata/{id}", web::get().to(api::public::metadata))
+                    // Auth only identifies the caller here, anyone without admin rights needs the deletion token
+                    .service(web::resource("/file/{id}").wrap(uploader_auth.clone()).route(web::patch().to(api::file::modify)).route(web::delete().to(api::file::delete)))
+                    .service(web::resource("/sign/{id}").wrap(whitelist.clone()).wrap(uploader_auth.clone()).route(web::post().to(api::private::sign)))
+                    .service(web::resource("/storage").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::private::storage)))
+                    .service(web::resource("/status").wrap(whitelist.clone()).wrap(admin_auth.clone()).route(web::get().to(api::private::status)))
+                    .service(web::resource("/upload").wrap(whitelist.clone()).wrap(uploader_auth.clone()).route(web::post().to(api::private::upload)))
+                    .service(web::resource("/usage").wrap(whitelist.clone()).wrap(uploader_auth.clone()).route(web::get().to(api::private::usage))),
             )
     })
     .bind((config.host, config.port))?
     .run()
-    .await
+    .await?;
+
+    // Actix finished the requests in flight on SIGTERM, what they queued still has to reach the database
+    shutdown_cache.shutdown().await;
+    Ok(())
 }
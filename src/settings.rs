@@ -1,5 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::Path;
 
 pub fn config_path() -> &'static str {
     #[cfg(target_os = "windows")]
@@ -23,7 +24,66 @@ pub enum ConfigError {
 impl Configuration {
     pub fn load(path: &str) -> Result<Self, ConfigError> {
         let content = fs::read_to_string(path).map_err(|e| ConfigError::IoError(e))?;
-        toml::from_str::<Configuration>(&content).map_err(|e| ConfigError::TomlError(e))
+        let mut config = toml::from_str::<Configuration>(&content).map_err(|e| ConfigError::TomlError(e))?;
+        // The database lives in the library unless told otherwise
+        if config.cache.database_path.is_empty() {
+            config.cache.database_path = Path::new(&config.cache_path).join(crate::cache::database::DATABASE_FILE).to_string_lossy().into_owned();
+        }
+        Ok(config)
+    }
+}
+
+// What the diagnostics endpoint shows of the configuration, tokens and keys are only named
+#[derive(Serialize)]
+pub struct ConfigSummary {
+    pub service_name: String,
+    pub host: String,
+    pub port: u16,
+    pub cache_path: String,
+    pub database_path: String,
+    pub ip_whitelist: Vec<String>,
+    pub forward_header: Option<String>,
+    pub max_item_size: usize,
+    pub max_cache_memory: usize,
+    pub in_memory_ttl: usize,
+    pub on_disk_ttl: usize,
+    pub encryption: bool,
+    pub trash_grace_period: Option<u64>,
+    pub scrub_rate: Option<u64>,
+    pub max_library_size: Option<u64>,
+    pub min_free_space: Option<u64>,
+    pub signing_keys: Vec<String>,
+    pub api_tokens: Vec<TokenSummary>,
+}
+
+#[derive(Serialize)]
+pub struct TokenSummary {
+    pub name: String,
+    pub role: crate::users::Role,
+}
+
+impl From<&Configuration> for ConfigSummary {
+    fn from(config: &Configuration) -> Self {
+        Self {
+            service_name: config.service_name.clone(),
+            host: config.host.clone(),
+            port: config.port,
+            cache_path: config.cache_path.clone(),
+            database_path: config.cache.database_path.clone(),
+            ip_whitelist: config.ip_whitelist.clone(),
+            forward_header: config.forward_header.clone(),
+            max_item_size: config.cache.max_item_size,
+            max_cache_memory: config.cache.max_cache_memory,
+            in_memory_ttl: config.cache.in_memory_ttl,
+            on_disk_ttl: config.cache.on_disk_ttl,
+            encryption: config.cache.encryption.enabled,
+            trash_grace_period: config.cache.trash.grace_period,
+            scrub_rate: config.cache.scrub.rate,
+            max_library_size: config.cache.storage.max_library_size,
+            min_free_space: config.cache.storage.min_free_space,
+            signing_keys: config.signing.keys.iter().map(|key| key.id.clone()).collect(),
+            api_tokens: config.api_tokens.iter().map(|token| TokenSummary { name: token.name.clone(), role: token.role }).collect(),
+        }
     }
 }
 
@@ -52,6 +112,19 @@ pub struct Configuration {
 
     #[serde(default)]
     pub forward_header: Option<String>,
+
+    #[serde(default)]
+    pub signing: SigningConfig,
+
+    #[serde(default)]
+    pub password: PasswordConfig,
+
+    #[serde(default)]
+    pub users: UsersConfig,
+
+    // Static bearer tokens for CI jobs and other API clients
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
 }
 
 fn default_port() -> u16 {
@@ -76,7 +149,7 @@ fn default_cache_path() -> String {
     }
     #[cfg(target_os = "linux")]
     {
-        "/tmp".into()
+        "/var/lib/korvatunturi-box/library".into()
     }
 }
 
@@ -94,7 +167,8 @@ pub struct CacheConfig {
     #[serde(default = "default_file_cleanup_interval")]
     pub file_cleanup_interval: usize,
 
-    #[serde(default = "default_database_path")]
+    // A path or `sqlite:` url, defaults to a file in the library. ":memory:" keeps nothing across restarts
+    #[serde(default)]
     pub database_path: String,
 
     #[serde(default = "default_maximum_size")]
@@ -102,6 +176,39 @@ pub struct CacheConfig {
 
     #[serde(default = "default_maximum_size")]
     pub max_cache_memory: usize,
+
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+
+    #[serde(default)]
+    pub quota: QuotaConfig,
+
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    #[serde(default)]
+    pub ttl: TtlConfig,
+
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    #[serde(default)]
+    pub sliding: SlidingConfig,
+
+    #[serde(default)]
+    pub trash: TrashConfig,
+
+    #[serde(default)]
+    pub consistency: ConsistencyConfig,
+
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+
+    #[serde(default)]
+    pub database: DatabaseConfig,
+
+    #[serde(default)]
+    pub queue: QueueConfig,
 }
 
 impl Default for CacheConfig {
@@ -111,12 +218,317 @@ impl Default for CacheConfig {
             cache_cleanup_interval: default_on_disk_ttl(),
             on_disk_ttl: default_file_cleanup_interval(),
             file_cleanup_interval: default_cache_cleanup_interval(),
-            database_path: default_database_path(),
+            database_path: String::new(),
             max_item_size: default_maximum_size(),
             max_cache_memory: default_max_cache_memory(),
+            encryption: EncryptionConfig::default(),
+            quota: QuotaConfig::default(),
+            storage: StorageConfig::default(),
+            ttl: TtlConfig::default(),
+            retention: RetentionConfig::default(),
+            sliding: SlidingConfig::default(),
+            trash: TrashConfig::default(),
+            consistency: ConsistencyConfig::default(),
+            scrub: ScrubConfig::default(),
+            database: DatabaseConfig::default(),
+            queue: QueueConfig::default(),
+        }
+    }
+}
+// Limits left out are unlimited
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct QuotaLimits {
+    #[serde(default)]
+    pub max_live_bytes: Option<u64>,
+
+    #[serde(default)]
+    pub max_live_files: Option<u64>,
+
+    #[serde(default)]
+    pub max_daily_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QuotaConfig {
+    // Applies to every owner
+    #[serde(default, flatten)]
+    pub default: QuotaLimits,
+
+    // Owner ("user:<name>", "token:<name>" or "ip:<address>") -> limits, unset fields fall back to the defaults
+    #[serde(default)]
+    pub overrides: std::collections::HashMap<String, QuotaLimits>,
+}
+
+// Limits left out are unrestricted, in seconds
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct TtlPolicy {
+    #[serde(default)]
+    pub min_ttl: Option<u64>,
+
+    #[serde(default)]
+    pub max_ttl: Option<u64>,
+
+    // Whether `expires_at = "never"` is accepted
+    #[serde(default)]
+    pub allow_never: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TtlConfig {
+    #[serde(default, flatten)]
+    pub default: TtlPolicy,
+
+    // ACL group ("anonymous", "uploader" or "admin") -> policy, unset fields fall back to the defaults
+    #[serde(default)]
+    pub groups: std::collections::HashMap<String, TtlPolicy>,
+}
+
+// Maximum lifetime by file size, off unless both ages are set, in seconds
+#[derive(Debug, Default, Deserialize)]
+pub struct RetentionConfig {
+    // Lifetime of a file at `max_size`
+    #[serde(default)]
+    pub min_age: Option<u64>,
+
+    // Lifetime of an empty file
+    #[serde(default)]
+    pub max_age: Option<u64>,
+
+    // Defaults to `max_item_size`, in bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+// Uploads made with `sliding=true` get their expiry pushed back on every download, in seconds
+#[derive(Debug, Deserialize)]
+pub struct SlidingConfig {
+    // How far a download pushes the expiry ahead, sliding expiry is off when left out
+    #[serde(default)]
+    pub window: Option<u64>,
+
+    // Counted from the upload, the expiry never slides past this
+    #[serde(default = "default_sliding_hard_cap")]
+    pub hard_cap: u64,
+}
+
+impl Default for SlidingConfig {
+    fn default() -> Self {
+        Self {
+            window: None,
+            hard_cap: default_sliding_hard_cap(),
+        }
+    }
+}
+fn default_sliding_hard_cap() -> u64 {
+    // 90 days
+    7_776_000
+}
+
+// Deleted and expired files are kept around for a while before being purged, in seconds
+#[derive(Debug, Deserialize)]
+pub struct TrashConfig {
+    // How long a deleted file can still be restored, files are deleted right away when left out
+    #[serde(default)]
+    pub grace_period: Option<u64>,
+
+    #[serde(default = "default_trash_sweep_interval")]
+    pub sweep_interval: usize,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: None,
+            sweep_interval: default_trash_sweep_interval(),
+        }
+    }
+}
+fn default_trash_sweep_interval() -> usize {
+    300
+}
+
+// Finds rows without blobs, blobs without rows and blobs of the wrong size
+#[derive(Debug, Deserialize)]
+pub struct ConsistencyConfig {
+    // "drop" or "quarantine"
+    #[serde(default)]
+    pub policy: crate::cache::consistency::RepairPolicy,
+
+    // Also runs at startup, in seconds
+    #[serde(default = "default_consistency_interval")]
+    pub interval: usize,
+}
+
+impl Default for ConsistencyConfig {
+    fn default() -> Self {
+        Self {
+            policy: Default::default(),
+            interval: default_consistency_interval(),
+        }
+    }
+}
+fn default_consistency_interval() -> usize {
+    // 6 hours
+    21_600
+}
+
+// Re-hashes blobs in the background to catch corruption
+#[derive(Debug, Deserialize)]
+pub struct ScrubConfig {
+    // Bytes per second, the scrubber doesn't run without it
+    #[serde(default)]
+    pub rate: Option<u64>,
+
+    // How long a blob stays verified before it's hashed again, in seconds
+    #[serde(default = "default_scrub_interval")]
+    pub interval: usize,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self { rate: None, interval: default_scrub_interval() }
+    }
+}
+fn default_scrub_interval() -> usize {
+    // 7 days
+    604_800
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DatabaseConfig {
+    // How long a query waits on a locked database, in milliseconds
+    #[serde(default = "default_busy_timeout")]
+    pub busy_timeout: u64,
+
+    // Checkpoint, ANALYZE and VACUUM, in seconds
+    #[serde(default = "default_maintenance_interval")]
+    pub maintenance_interval: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            busy_timeout: default_busy_timeout(),
+            maintenance_interval: default_maintenance_interval(),
         }
     }
 }
+fn default_busy_timeout() -> u64 {
+    5_000
+}
+fn default_maintenance_interval() -> usize {
+    // 1 day
+    86_400
+}
+
+// Metadata writes from the background routines are grouped into one transaction
+#[derive(Debug, Deserialize)]
+pub struct QueueConfig {
+    // Most signals applied in one transaction
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+
+    // How long a batch waits to fill up, in milliseconds
+    #[serde(default = "default_batch_latency")]
+    pub batch_latency: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: default_batch_size(),
+            batch_latency: default_batch_latency(),
+        }
+    }
+}
+fn default_batch_size() -> usize {
+    256
+}
+fn default_batch_latency() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageConfig {
+    // Upper bound for the files in the library, in bytes
+    #[serde(default)]
+    pub max_library_size: Option<u64>,
+
+    // Uploads are refused once they would leave less than this free on the filesystem, in bytes
+    #[serde(default)]
+    pub min_free_space: Option<u64>,
+
+    #[serde(default = "default_disk_monitor_interval")]
+    pub monitor_interval: usize,
+
+    // Evict the files closest to expiring instead of refusing uploads
+    #[serde(default)]
+    pub evict_to_make_room: bool,
+
+    // Only log the orphaned files startup cleanup would remove
+    #[serde(default)]
+    pub cleanup_dry_run: bool,
+
+    // Restore the database from the sidecars when it comes up empty next to a populated library
+    #[serde(default)]
+    pub rebuild_empty_index: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            max_library_size: None,
+            min_free_space: None,
+            monitor_interval: default_disk_monitor_interval(),
+            evict_to_make_room: false,
+            cleanup_dry_run: false,
+            rebuild_empty_index: false,
+        }
+    }
+}
+fn default_disk_monitor_interval() -> usize {
+    30
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EncryptionConfig {
+    // Encrypts new uploads, existing blobs are readable as long as the key is available
+    #[serde(default)]
+    pub enabled: bool,
+
+    // Hex encoded 32 byte keys, the file takes precedence over the environment variable
+    #[serde(default)]
+    pub master_key_file: Option<String>,
+
+    #[serde(default = "default_master_key_env")]
+    pub master_key_env: String,
+
+    // The key being rotated out, see `rotate-keys`
+    #[serde(default)]
+    pub previous_master_key_file: Option<String>,
+
+    #[serde(default = "default_previous_master_key_env")]
+    pub previous_master_key_env: String,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            master_key_file: None,
+            master_key_env: default_master_key_env(),
+            previous_master_key_file: None,
+            previous_master_key_env: default_previous_master_key_env(),
+        }
+    }
+}
+fn default_master_key_env() -> String {
+    "KORVATUNTURI_MASTER_KEY".into()
+}
+fn default_previous_master_key_env() -> String {
+    "KORVATUNTURI_PREVIOUS_MASTER_KEY".into()
+}
+
 fn default_in_memory_ttl() -> usize {
     30
 }
@@ -129,9 +541,6 @@ fn default_file_cleanup_interval() -> usize {
 fn default_cache_cleanup_interval() -> usize {
     5
 }
-fn default_database_path() -> String {
-    ":memory:".to_string()
-}
 fn default_maximum_size() -> usize {
     // 200 mb
     200_000_000
@@ -140,3 +549,117 @@ fn default_max_cache_memory() -> usize {
     // 10 gb
     10_000_000_000
 }
+
+#[derive(Debug, Deserialize)]
+pub struct SigningConfig {
+    // Every listed key is accepted when verifying, only the active one is used for signing
+    #[serde(default)]
+    pub keys: Vec<SigningKey>,
+
+    // Defaults to the first key in the list
+    #[serde(default)]
+    pub active_key: Option<String>,
+
+    #[serde(default = "default_signed_url_ttl")]
+    pub default_ttl: usize,
+
+    #[serde(default = "default_signed_url_max_ttl")]
+    pub max_ttl: usize,
+
+    // Origin signed links point at, e.g. "https://box.example.org". Without it only the path is handed out
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+// The config gets dumped to the debug log, keep the secret out of it
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey").field("id", &self.id).field("secret", &"<redacted>").finish()
+    }
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            active_key: None,
+            default_ttl: default_signed_url_ttl(),
+            max_ttl: default_signed_url_max_ttl(),
+            public_url: None,
+        }
+    }
+}
+fn default_signed_url_ttl() -> usize {
+    // 1 hour
+    3600
+}
+fn default_signed_url_max_ttl() -> usize {
+    // 7 days
+    604_800
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordConfig {
+    // Failed password attempts allowed per client and file within the window
+    #[serde(default = "default_password_attempts")]
+    pub max_attempts: usize,
+
+    #[serde(default = "default_password_attempt_window")]
+    pub attempt_window: usize,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_password_attempts(),
+            attempt_window: default_password_attempt_window(),
+        }
+    }
+}
+fn default_password_attempts() -> usize {
+    5
+}
+fn default_password_attempt_window() -> usize {
+    // 15 minutes
+    900
+}
+
+#[derive(Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub token: String,
+    #[serde(default = "default_token_role")]
+    pub role: crate::users::Role,
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken").field("name", &self.name).field("token", &"<redacted>").field("role", &self.role).finish()
+    }
+}
+fn default_token_role() -> crate::users::Role {
+    crate::users::Role::Uploader
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsersConfig {
+    // How long a login stays valid
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: usize,
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self { session_ttl: default_session_ttl() }
+    }
+}
+fn default_session_ttl() -> usize {
+    // 12 hours
+    43_200
+}
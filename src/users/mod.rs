@@ -0,0 +1,60 @@
+mod store;
+
+pub use store::UserStore;
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Uploader,
+    Admin,
+}
+
+impl Role {
+    // Admins can do everything uploaders can
+    pub fn allows(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Uploader => write!(f, "uploader"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uploader" => Ok(Role::Uploader),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// Inserted into the request extensions once a user has been authenticated
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub role: Role,
+    // Authenticated with an API token instead of a user account
+    pub via_token: bool,
+}
+
+impl AuthenticatedUser {
+    // Quotas and file ownership are tracked per owner
+    pub fn owner(&self) -> String {
+        match self.via_token {
+            true => format!("token:{}", self.username),
+            false => format!("user:{}", self.username),
+        }
+    }
+}
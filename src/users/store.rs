@@ -0,0 +1,200 @@
+use super::{AuthenticatedUser, Role};
+use crate::cache::{FileCache, settings::CacheSettings};
+use crate::settings::ApiToken;
+use argon2::{
+    Argon2,
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use chrono::Utc;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+#[derive(Debug)]
+pub enum UserStoreError {
+    #[allow(unused)]
+    DbError(sqlx::Error),
+    PasswordHashing,
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    password_hash: String,
+    role: String,
+}
+
+struct Session {
+    user: AuthenticatedUser,
+    expires: Instant,
+}
+
+pub struct UserStore {
+    pool: sqlx::Pool<sqlx::Sqlite>,
+    // Session token -> session, logins don't survive a restart
+    sessions: RwLock<HashMap<String, Session>>,
+    session_ttl: Duration,
+    // SHA-256 of the token -> the token's identity, tokens come from the configuration
+    tokens: HashMap<String, AuthenticatedUser>,
+}
+
+fn token_digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl UserStore {
+    pub async fn new(pool: sqlx::Pool<sqlx::Sqlite>, session_ttl: Duration) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS users (
+                username TEXT NOT NULL PRIMARY KEY,
+                password_hash TEXT NOT NULL,
+                role TEXT NOT NULL,
+                created_utc TEXT NOT NULL
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+        debug!("users table initialized");
+
+        Ok(Self {
+            pool,
+            sessions: RwLock::new(HashMap::new()),
+            session_ttl,
+            tokens: HashMap::new(),
+        })
+    }
+
+    pub fn with_tokens(mut self, tokens: &[ApiToken]) -> Self {
+        for token in tokens {
+            if token.token.is_empty() {
+                warn!("API token {} is empty, ignoring it", token.name);
+                continue;
+            }
+            let user = AuthenticatedUser {
+                username: token.name.clone(),
+                role: token.role,
+                via_token: true,
+            };
+            self.tokens.insert(token_digest(&token.token), user);
+        }
+        self
+    }
+
+    pub fn token(&self, token: &str) -> Option<AuthenticatedUser> {
+        self.tokens.get(&token_digest(token)).cloned()
+    }
+
+    // For the user management commands, which run without the cache
+    pub async fn connect(cache_settings: &CacheSettings) -> Result<Self, sqlx::Error> {
+        let pool = FileCache::connect_database(cache_settings).await?;
+        Self::new(pool, Duration::ZERO).await
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    pub async fn add_user(&self, username: &str, password: &str, role: Role) -> Result<(), UserStoreError> {
+        let password = password.to_string();
+        let hash = tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+        })
+        .await
+        .map_err(|_| UserStoreError::PasswordHashing)?
+        .map_err(|_| UserStoreError::PasswordHashing)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (username, password_hash, role, created_utc)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(username) DO UPDATE SET password_hash = excluded.password_hash, role = excluded.role
+        "#,
+        )
+        .bind(username)
+        .bind(hash)
+        .bind(role.to_string())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .map_err(UserStoreError::DbError)?;
+        Ok(())
+    }
+
+    pub async fn remove_user(&self, username: &str) -> Result<bool, UserStoreError> {
+        let result = sqlx::query("DELETE FROM users WHERE username = ?").bind(username).execute(&self.pool).await.map_err(UserStoreError::DbError)?;
+
+        // Kill any sessions the user still has
+        let mut sessions = self.sessions.write().await;
+        sessions.retain(|_, session| session.user.username != username);
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn authenticate(&self, username: &str, password: &str) -> Option<AuthenticatedUser> {
+        let row: UserRow = match sqlx::query_as("SELECT password_hash, role FROM users WHERE username = ?").bind(username).fetch_optional(&self.pool).await {
+            Ok(row) => row?,
+            Err(e) => {
+                warn!("Error looking up user {}: {}", username, e);
+                return None;
+            }
+        };
+
+        // Argon2 is slow on purpose
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || match PasswordHash::new(&row.password_hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(_) => false,
+        })
+        .await
+        .unwrap_or(false);
+        if !valid {
+            return None;
+        }
+
+        match row.role.parse::<Role>() {
+            Ok(role) => Some(AuthenticatedUser {
+                username: username.to_string(),
+                role,
+                via_token: false,
+            }),
+            Err(_) => {
+                warn!("User {} has an unknown role {}", username, row.role);
+                None
+            }
+        }
+    }
+
+    pub async fn create_session(&self, user: AuthenticatedUser) -> String {
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = hex::encode(token);
+
+        let mut sessions = self.sessions.write().await;
+        // Expired sessions are dropped whenever a new one is created
+        sessions.retain(|_, session| session.expires > Instant::now());
+        sessions.insert(
+            token.clone(),
+            Session {
+                user,
+                expires: Instant::now() + self.session_ttl,
+            },
+        );
+        token
+    }
+
+    pub async fn session(&self, token: &str) -> Option<AuthenticatedUser> {
+        let sessions = self.sessions.read().await;
+        sessions.get(token).filter(|session| session.expires > Instant::now()).map(|session| session.user.clone())
+    }
+
+    pub async fn end_session(&self, token: &str) {
+        let mut sessions = self.sessions.write().await;
+        sessions.remove(token);
+    }
+}